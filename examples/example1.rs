@@ -7,6 +7,8 @@ use btrs::errors::*;
 use btrs::units::*;
 use btrs::wal::*;
 use btrs::page::*;
+use btrs::vfs::OsVfs;
+use std::sync::Arc;
 
 fn main() {
     run().unwrap();
@@ -16,25 +18,26 @@ fn run() -> Result<()> {
     fs::remove_file("./testdb.wal")?;
     fs::remove_file("./testdb.shm")?;
     let ps = PageSize::new(512);
-    let mut wal = Wal::new("./testdb.db", ps)?;
+    let mut wal = Wal::new(Arc::new(OsVfs), "./testdb.db", ps)?;
     {
         let mut wal = wal.begin_read()?;
         let mut wal = wal.begin_write()?;
         let mut page = Page::new(ps);
         page.buf_mut()[0] = 1;
-        wal.write_page(0, page)?;
+        wal.set_db_size(2);
+        wal.write_page(1, page)?;
         wal.commit()?;
     }
     wal.dump();
     {
         let mut wal = wal.begin_read()?;
-        let page = wal.read_page(0)?.unwrap();
+        let page = wal.read_page(1)?.unwrap();
         assert!(page.buf()[0] == 1);
     }
     wal.dump();
     {
-        let wal = wal.begin_checkpoint()?;
-        wal.next_epoch()?;
+        let wal = wal.begin_checkpoint(CheckpointMode::Restart)?;
+        wal.finish()?;
     }
     println!("checkpoint");
     wal.dump();
@@ -43,6 +46,7 @@ fn run() -> Result<()> {
         let mut wal = wal.begin_write()?;
         let mut page = Page::new(ps);
         page.buf_mut()[0] = 1;
+        wal.set_db_size(2);
         wal.write_page(1, page)?;
         let mut page = Page::new(ps);
         page.buf_mut()[0] = 1;
@@ -58,19 +62,32 @@ fn run() -> Result<()> {
         wal.commit()?;
     }
     wal.dump();
+    {
+        let mut wal = wal.begin_read()?;
+        let mut wal = wal.begin_write()?;
+        let mut page = Page::new(ps);
+        page.buf_mut()[0] = 3;
+        wal.write_page(2, page)?;
+        let mut page = Page::new(ps);
+        page.buf_mut()[0] = 3;
+        wal.write_page(3, page)?;
+        wal.rollback()?;
+    }
+    wal.dump();
     {
         let mut wal = wal.begin_read()?;
         let page = wal.read_page(2)?.unwrap();
         assert!(page.buf()[0] == 2);
+        assert!(wal.read_page(3)?.is_none());
     }
     wal.dump();
     {
-        let wal = wal.begin_checkpoint()?;
+        let wal = wal.begin_checkpoint(CheckpointMode::Restart)?;
         for page in wal.pages() {
             println!("replaying page {}", page);
             let _page = wal.read_page(*page)?;
         }
-        wal.next_epoch()?;
+        wal.finish()?;
     }
     wal.dump();
     {
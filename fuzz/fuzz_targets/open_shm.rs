@@ -0,0 +1,11 @@
+#![no_main]
+
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate btrs_fuzz;
+
+fuzz_target!(|data: &[u8]| {
+    let vfs = btrs_fuzz::database();
+    btrs_fuzz::replace_shm(&vfs, data);
+    btrs_fuzz::exercise(&vfs);
+});
@@ -0,0 +1,101 @@
+//! Shared setup for the fuzz targets
+//!
+//! Each target replaces one file of an in-memory database with the
+//! fuzzer's bytes, then opens the database, which recovers the log,
+//! and runs a few transactions on it. Any of that may fail, since the
+//! file is garbage, but none of it may panic.
+//!
+//! Run a target with e.g. `cargo fuzz run open_wal` from the parent
+//! directory.
+
+extern crate btrs;
+
+use btrs::lock::BusyHandler;
+use btrs::mem_vfs::MemVfs;
+use btrs::page::Page;
+use btrs::units::PageSize;
+use btrs::vfs::Vfs;
+use btrs::wabl::*;
+use btrs::wal::CheckpointMode;
+use btrs::wal_index::REGION_SIZE;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::Arc;
+
+const PATH: &'static str = "fuzz";
+const PAGE_SIZE: u32 = 4096;
+/// The most pages to read, so that a huge database size can't make a
+/// run take forever
+const MAX_READS: u32 = 16;
+
+fn filled(b: u8) -> Page {
+    let mut page = Page::new(PageSize::new(PAGE_SIZE));
+    for x in page.buf_mut() {
+        *x = b;
+    }
+    page
+}
+
+fn path(ext: &str) -> PathBuf {
+    Path::new(PATH).with_extension(ext)
+}
+
+/// Creates a database with pages in both the database file and the
+/// log, so that whichever file the fuzzer replaces, the others are
+/// worth recovering
+pub fn database() -> MemVfs {
+    let vfs = MemVfs::new();
+    let mut w = Wabl::new(Arc::new(vfs.clone()), &PATH).expect("create");
+    w.set_auto_checkpoint(AutoCheckpoint::Disabled);
+    for n in 1..5 {
+        let mut tx = w.begin_read().expect("read").begin_write().expect("write");
+        tx.write_page(n, filled(n as u8)).expect("write page");
+        tx.commit().expect("commit");
+        if n == 2 {
+            w.checkpoint(CheckpointMode::Passive).expect("checkpoint");
+        }
+    }
+    vfs
+}
+
+/// Replaces the file with extension `ext`, `db` or `wal`, with `data`
+pub fn replace_file(vfs: &MemVfs, ext: &str, data: &[u8]) {
+    let file = vfs.open(&path(ext)).expect("open");
+    file.set_len(0).expect("truncate");
+    file.write_at(data, 0).expect("write");
+}
+
+/// Replaces the start of the shared memory with `data`, as left
+/// behind by a connection that crashed or by something else entirely
+pub fn replace_shm(vfs: &MemVfs, data: &[u8]) {
+    let shm = vfs.open_shm(&path("shm")).expect("open shm");
+    for (n, chunk) in data.chunks(REGION_SIZE).enumerate() {
+        let region = shm.region(n as u32, REGION_SIZE).expect("region");
+        unsafe { ptr::copy_nonoverlapping(chunk.as_ptr(), region, chunk.len()) }
+    }
+}
+
+/// Opens the database and runs a read, a write and a checkpoint,
+/// ignoring any errors
+pub fn exercise(vfs: &MemVfs) {
+    let mut w = match Wabl::new(Arc::new(vfs.clone()), &PATH) {
+        Ok(w) => w,
+        Err(_) => return,
+    };
+    // Nothing else is running, so waiting would never end
+    w.set_busy_handler(BusyHandler::Callback(Arc::new(|_| false)));
+
+    if let Ok(mut tx) = w.begin_read() {
+        if let Ok(n) = tx.db_size() {
+            // Page 0 holds the header, which opening already read
+            for i in 1..n.min(MAX_READS) {
+                let _ = tx.read_page(i);
+            }
+        }
+        if let Ok(mut tx) = tx.begin_write() {
+            let _ = tx.write_page(1, filled(0xff));
+            let _ = tx.commit();
+        }
+    }
+    let _ = w.checkpoint(CheckpointMode::Truncate);
+}
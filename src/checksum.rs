@@ -0,0 +1,39 @@
+//! Cumulative checksums for on-disk structures
+//!
+//! This is the Fletcher-like checksum used by SQLite's WAL. It is
+//! computed over pairs of little-endian 32-bit words and can be
+//! chained, so that the checksum of one structure seeds the next.
+
+use byteorder::*;
+use std::fmt;
+
+#[derive(Copy, Clone, Default, Debug, Eq, PartialEq)]
+pub struct Checksum(pub u32, pub u32);
+
+impl Checksum {
+    /// Extend the checksum over `buf`, whose length must be a
+    /// multiple of 4 bytes. An odd trailing word is paired with 0.
+    pub fn update(self, buf: &[u8]) -> Checksum {
+        assert_eq!(buf.len() % 4, 0);
+
+        let Checksum(mut s0, mut s1) = self;
+        for pair in buf.chunks(8) {
+            let x0 = LittleEndian::read_u32(&pair[0..4]);
+            let x1 = if pair.len() == 8 {
+                LittleEndian::read_u32(&pair[4..8])
+            } else {
+                0
+            };
+            s0 = s0.wrapping_add(x0).wrapping_add(s1);
+            s1 = s1.wrapping_add(x1).wrapping_add(s0);
+        }
+
+        Checksum(s0, s1)
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:08x}:{:08x}", self.0, self.1)
+    }
+}
@@ -0,0 +1,347 @@
+//! A `Vfs` that simulates power loss and failing syscalls, for
+//! testing crash consistency
+//!
+//! Files are kept in memory in two versions: what has reached the
+//! disk, as of the last sync, and what a reader sees, which also
+//! includes the writes since. `crash` throws the unsynced writes away
+//! the way a power failure might: each sector of each write survives
+//! or not at random, independent of the others, so writes can be
+//! lost, torn at sector boundaries, or land out of order. Shared
+//! memory doesn't survive a crash at all.
+//!
+//! Faults are triggered by counting I/O operations. `power_off_after`
+//! makes every operation fail after a given number, so a test can cut
+//! the power at each point of a workload in turn, and `fail_after`
+//! makes a single operation fail with a given errno.
+//!
+//! Creating a file is durable right away, so syncing directories is
+//! a no-op.
+
+use errors::*;
+use lock::LockKind;
+use mem_vfs::MemVfs;
+use vfs::{Vfs, VfsFile, Shm, write_error};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::io;
+use rand::{Rng, SeedableRng, XorShiftRng};
+use libc;
+
+/// The unit in which the disk writes. Writes can be torn at its
+/// boundaries.
+pub const SECTOR_SIZE: u64 = 512;
+
+#[derive(Clone)]
+pub struct FaultVfs(Arc<Mutex<State>>);
+
+/// A kind of I/O operation that can fail
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum Op {
+    Read,
+    Write,
+    Sync,
+    /// Changing the length of a file, including allocating space
+    SetLen,
+}
+
+struct State {
+    files: HashMap<PathBuf, Disk>,
+    /// Holds the shared memory and the file locks, which don't
+    /// survive a crash
+    mem: MemVfs,
+    rng: XorShiftRng,
+    /// The number of operations so far
+    ops: u64,
+    /// The number of operations of each kind so far
+    op_counts: HashMap<Op, u64>,
+    /// The number of operations after which the power goes off
+    power_off_at: Option<u64>,
+    faults: Vec<Fault>,
+}
+
+struct Fault {
+    op: Option<Op>,
+    /// The number of matching operations to let through first
+    skip: u64,
+    errno: i32,
+}
+
+#[derive(Default)]
+struct Disk {
+    /// The contents as of the last sync
+    durable: Vec<u8>,
+    /// The contents including unsynced changes
+    current: Vec<u8>,
+    /// The changes since the last sync, in order
+    pending: Vec<Change>,
+}
+
+enum Change {
+    Write(u64, Vec<u8>),
+    SetLen(u64),
+}
+
+struct FaultFile {
+    vfs: FaultVfs,
+    path: PathBuf,
+    /// A handle on the same path in `State::mem`, for locking
+    lock: Box<VfsFile>,
+}
+
+impl FaultVfs {
+    /// Creates an empty filesystem. `seed` decides which unsynced
+    /// writes survive a crash.
+    pub fn new(seed: u32) -> FaultVfs {
+        let seed = [seed, 0x193a6754, 0xa8a7d469, 0x97830e05];
+        FaultVfs(Arc::new(Mutex::new(State {
+            files: HashMap::new(),
+            mem: MemVfs::new(),
+            rng: XorShiftRng::from_seed(seed),
+            ops: 0,
+            op_counts: HashMap::new(),
+            power_off_at: None,
+            faults: Vec::new(),
+        })))
+    }
+
+    /// The number of operations so far. These are the points at
+    /// which the power can go off.
+    pub fn ops(&self) -> u64 {
+        self.0.lock().expect("fault vfs").ops
+    }
+
+    /// The number of operations of kind `op` so far, e.g. to check
+    /// how often a workload syncs
+    pub fn op_count(&self, op: Op) -> u64 {
+        self.0.lock().expect("fault vfs").op_counts.get(&op).cloned().unwrap_or(0)
+    }
+
+    /// Whether there is a file at `p`
+    pub fn exists(&self, p: &Path) -> bool {
+        self.0.lock().expect("fault vfs").files.contains_key(p)
+    }
+
+    /// Whether everything written to the file at `p` has been synced
+    pub fn is_synced(&self, p: &Path) -> bool {
+        self.0.lock().expect("fault vfs").files.get(p).into_iter().all(|d| d.pending.is_empty())
+    }
+
+    /// Makes every operation fail with `EIO` once `n` more operations
+    /// have run, until `crash`
+    pub fn power_off_after(&self, n: u64) {
+        let mut state = self.0.lock().expect("fault vfs");
+        state.power_off_at = Some(state.ops + n);
+    }
+
+    /// Makes the operation after the next `n` of kind `op`, or of any
+    /// kind if `None`, fail with `errno`, e.g. `libc::EIO` or
+    /// `libc::ENOSPC`, without doing anything
+    pub fn fail_after(&self, op: Option<Op>, n: u64, errno: i32) {
+        self.0.lock().expect("fault vfs").faults.push(Fault {
+            op: op,
+            skip: n,
+            errno: errno,
+        });
+    }
+
+    /// Simulates a power failure and restores the power. Every
+    /// connection using the `Vfs` must be dropped first.
+    pub fn crash(&self) {
+        let mut state = self.0.lock().expect("fault vfs");
+        let state = &mut *state;
+        for disk in state.files.values_mut() {
+            disk.crash(&mut state.rng);
+        }
+        state.mem = MemVfs::new();
+        state.power_off_at = None;
+        state.faults.clear();
+    }
+}
+
+impl State {
+    /// Accounts for an operation, failing if a fault says so
+    fn io(&mut self, op: Op) -> Result<()> {
+        if let Some(n) = self.power_off_at {
+            if self.ops >= n {
+                return Err(io::Error::from_raw_os_error(libc::EIO).into());
+            }
+        }
+        self.ops += 1;
+        *self.op_counts.entry(op).or_insert(0) += 1;
+
+        let mut failed = None;
+        for (i, fault) in self.faults.iter_mut().enumerate() {
+            if fault.op.is_some_and(|o| o != op) {
+                continue;
+            }
+            if fault.skip == 0 {
+                failed = Some(i);
+                break;
+            }
+            fault.skip -= 1;
+        }
+        if let Some(i) = failed {
+            let errno = self.faults.remove(i).errno;
+            return write_error(Err(io::Error::from_raw_os_error(errno)));
+        }
+
+        Ok(())
+    }
+}
+
+impl Disk {
+    fn write(&mut self, buf: &[u8], offset: u64) {
+        let end = offset as usize + buf.len();
+        if self.current.len() < end {
+            self.current.resize(end, 0);
+        }
+        self.current[offset as usize..end].copy_from_slice(buf);
+        self.pending.push(Change::Write(offset, buf.to_vec()));
+    }
+
+    fn set_len(&mut self, len: u64) {
+        self.current.resize(len as usize, 0);
+        self.pending.push(Change::SetLen(len));
+    }
+
+    fn sync(&mut self) {
+        self.durable = self.current.clone();
+        self.pending.clear();
+    }
+
+    /// Keeps a random selection of the unsynced changes
+    fn crash(&mut self, rng: &mut XorShiftRng) {
+        let mut disk = self.durable.clone();
+        for change in self.pending.drain(..) {
+            match change {
+                Change::Write(offset, buf) => {
+                    let end = offset + buf.len() as u64;
+                    let mut start = offset;
+                    while start < end {
+                        let sector_end = ((start / SECTOR_SIZE + 1) * SECTOR_SIZE).min(end);
+                        if rng.gen() {
+                            if (disk.len() as u64) < sector_end {
+                                disk.resize(sector_end as usize, 0);
+                            }
+                            let src = &buf[(start - offset) as usize..(sector_end - offset) as usize];
+                            disk[start as usize..sector_end as usize].copy_from_slice(src);
+                        }
+                        start = sector_end;
+                    }
+                }
+                Change::SetLen(len) => {
+                    if rng.gen() {
+                        disk.resize(len as usize, 0);
+                    }
+                }
+            }
+        }
+        self.durable = disk.clone();
+        self.current = disk;
+    }
+}
+
+impl Vfs for FaultVfs {
+    fn open(&self, path: &Path) -> Result<Box<VfsFile>> {
+        let mut state = self.0.lock().expect("fault vfs");
+        state.files.entry(path.to_path_buf()).or_default();
+        let lock = state.mem.open(path)?;
+        Ok(Box::new(FaultFile {
+            vfs: self.clone(),
+            path: path.to_path_buf(),
+            lock: lock,
+        }))
+    }
+
+    fn open_shm(&self, path: &Path) -> Result<Box<Shm>> {
+        self.0.lock().expect("fault vfs").mem.open_shm(path)
+    }
+
+    fn sync_dir(&self, path: &Path) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl FaultFile {
+    /// Runs `f` on the file's disk, unless `op` fails
+    fn with_disk<F, T>(&self, op: Op, f: F) -> Result<T>
+        where F: FnOnce(&mut Disk) -> Result<T>
+    {
+        let mut state = self.vfs.0.lock().expect("fault vfs");
+        state.io(op)?;
+        let disk = state.files.get_mut(&self.path).expect("disk");
+        f(disk)
+    }
+}
+
+impl VfsFile for FaultFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        self.with_disk(Op::Read, |disk| {
+            let start = offset as usize;
+            if start + buf.len() > disk.current.len() {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                          "failed to fill whole buffer").into());
+            }
+            buf.copy_from_slice(&disk.current[start..start + buf.len()]);
+            Ok(())
+        })
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> Result<()> {
+        self.with_disk(Op::Write, |disk| {
+            disk.write(buf, offset);
+            Ok(())
+        })
+    }
+
+    fn write_vectored_at(&self, bufs: &[&[u8]], offset: u64) -> Result<()> {
+        self.with_disk(Op::Write, |disk| {
+            let mut offset = offset;
+            for buf in bufs {
+                disk.write(buf, offset);
+                offset += buf.len() as u64;
+            }
+            Ok(())
+        })
+    }
+
+    fn len(&self) -> Result<u64> {
+        self.with_disk(Op::Read, |disk| Ok(disk.current.len() as u64))
+    }
+
+    fn set_len(&self, len: u64) -> Result<()> {
+        self.with_disk(Op::SetLen, |disk| {
+            disk.set_len(len);
+            Ok(())
+        })
+    }
+
+    fn allocate(&self, len: u64) -> Result<()> {
+        self.with_disk(Op::SetLen, |disk| {
+            if (disk.current.len() as u64) < len {
+                disk.set_len(len);
+            }
+            Ok(())
+        })
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.with_disk(Op::Sync, |disk| {
+            disk.sync();
+            Ok(())
+        })
+    }
+
+    fn lock(&self, kind: LockKind) -> Result<()> {
+        self.lock.lock(kind)
+    }
+
+    fn try_lock(&self, kind: LockKind) -> Result<bool> {
+        self.lock.try_lock(kind)
+    }
+
+    fn unlock(&self) -> Result<()> {
+        self.lock.unlock()
+    }
+}
@@ -0,0 +1,407 @@
+//! The layout of the structures stored in the database and log files
+//!
+//! Each structure has an encoder and a decoder here, and nothing else
+//! reads or writes them. Decoders are nom parsers. The bytes come
+//! from files that may be truncated, corrupt or not ours at all, so
+//! decoding never panics: input that is too short or out of range is
+//! an `Invalid`, which the caller, knowing where the bytes came from,
+//! turns into a `Corruption`.
+//!
+//! Integers are little-endian. The Wal index isn't here, since it is
+//! shared memory used in place rather than decoded, and checks
+//! itself with its own checksum.
+
+use errors::*;
+use units::PageSize;
+use checksum::Checksum;
+use integrity::Corruption;
+use wal::{PageNum, FrameNum};
+use wal_index::IndexHeader;
+use byteorder::*;
+use nom::{IResult, Needed, le_u32, le_u64};
+use rand;
+use std::error;
+use std::fmt;
+use std::path::Path;
+use std::result;
+
+/// The header at the start of page 0 of the database, which holds
+/// nothing else. It is written through the log like any other page,
+/// on every commit.
+///
+/// The magic, page size and version never change, so they can be
+/// trusted even in a torn write of page 0.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DbHeader {
+    pub magic: u64,
+    pub page_size: PageSize,
+    /// The version of this file format, `DB_VERSION` for new databases
+    pub version: u32,
+    /// The size of the database in pages, including page 0
+    pub page_count: PageNum,
+    /// The first trunk of the freelist, or 0 if no page is free
+    pub freelist_trunk: PageNum,
+    /// The number of free pages, trunks included
+    pub freelist_count: u32,
+    /// Incremented by every commit
+    pub change_counter: u32,
+    /// The root page of the schema, for the layers above, or 0
+    pub schema_root: PageNum,
+    /// Free for the application to identify its files
+    pub application_id: u32,
+    /// Free for the application to version its schema
+    pub user_version: u32,
+}
+
+/// The current version of the database format
+pub const DB_VERSION: u32 = 1;
+/// The checksummed portion of the database header
+pub const DB_HEADER_DATA_SIZE: u32 = 44;
+/// The database header followed by its checksum
+pub const DB_HEADER_SIZE: u32 = DB_HEADER_DATA_SIZE + 8;
+/// Where the page size is in the database header
+pub const DB_HEADER_PAGE_SIZE_OFFSET: u32 = 8;
+/// Where the format version is in the database header
+pub const DB_HEADER_VERSION_OFFSET: u32 = 12;
+/// Where the number of pages is in the database header
+pub const DB_HEADER_PAGE_COUNT_OFFSET: u32 = 16;
+/// Where the first freelist trunk is in the database header
+pub const DB_HEADER_FREELIST_TRUNK_OFFSET: u32 = 20;
+/// Where the number of free pages is in the database header
+pub const DB_HEADER_FREELIST_COUNT_OFFSET: u32 = 24;
+
+/// A free page that lists other free pages, the leaves, which hold
+/// nothing. Trunks form a chain from the freelist header.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Trunk {
+    /// The next trunk, or 0 for the last
+    pub next: PageNum,
+    pub leaves: Vec<PageNum>,
+}
+
+/// The space before the leaves in a trunk page
+pub const TRUNK_HEADER_SIZE: u32 = 8;
+
+/// The header at the start of the log
+#[derive(Debug, Eq, PartialEq)]
+pub struct WalHeader {
+    pub magic: u64,
+    pub page_size: PageSize,
+    pub epoch: u64,
+    pub salt: Salt,
+}
+
+/// The checksummed portion of the Wal header
+pub const WAL_HEADER_DATA_SIZE: u32 = 28;
+/// The Wal header followed by its checksum
+pub const WAL_HEADER_SIZE: u32 = WAL_HEADER_DATA_SIZE + 8;
+
+/// The header of each frame in the log, followed by the cumulative
+/// checksum through the frame
+pub struct FrameHeader {
+    pub page_num: PageNum,
+    /// For the last frame of a transaction, the size of the database
+    /// in pages after it. 0 for every other frame.
+    pub commit: PageNum,
+    pub epoch: u64,
+    pub salt: Salt,
+}
+
+/// The checksummed portion of the frame header
+pub const FRAME_HEADER_DATA_SIZE: u32 = 24;
+/// The frame header followed by the checksum
+pub const FRAME_HEADER_SIZE: u32 = FRAME_HEADER_DATA_SIZE + 8;
+
+/// Random values chosen at the start of every epoch. Frames are only
+/// valid if they carry the same salt as the Wal header, so frames
+/// left over from an earlier log can never be replayed, even if they
+/// happen to share an epoch number.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Salt(pub u32, pub u32);
+
+/// What is wrong with bytes that failed to decode
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Invalid {
+    /// Where the problem is, from the start of the bytes decoded
+    pub offset: u64,
+    pub expected: String,
+    pub found: String,
+}
+
+pub type Decoded<T> = result::Result<T, Invalid>;
+
+named!(page_size<PageSize>, map_opt!(le_u32, PageSize::try_new));
+
+named!(salt<Salt>, do_parse!(
+    s0: le_u32 >>
+    s1: le_u32 >>
+    (Salt(s0, s1))
+));
+
+named!(checksum<Checksum>, do_parse!(
+    s0: le_u32 >>
+    s1: le_u32 >>
+    (Checksum(s0, s1))
+));
+
+named!(db_header<(DbHeader, Checksum)>, do_parse!(
+    magic: le_u64 >>
+    page_size: page_size >>
+    version: le_u32 >>
+    page_count: le_u32 >>
+    freelist_trunk: le_u32 >>
+    freelist_count: le_u32 >>
+    change_counter: le_u32 >>
+    schema_root: le_u32 >>
+    application_id: le_u32 >>
+    user_version: le_u32 >>
+    checksum: checksum >>
+    (DbHeader {
+        magic: magic,
+        page_size: page_size,
+        version: version,
+        page_count: page_count,
+        freelist_trunk: freelist_trunk,
+        freelist_count: freelist_count,
+        change_counter: change_counter,
+        schema_root: schema_root,
+        application_id: application_id,
+        user_version: user_version,
+    }, checksum)
+));
+
+named!(trunk_header<(PageNum, u32)>, tuple!(le_u32, le_u32));
+
+/// The leaves of a trunk, whose number must already be known to fit
+fn trunk_leaves(buf: &[u8], n: u32) -> IResult<&[u8], Vec<PageNum>> {
+    count!(buf, le_u32, n as usize)
+}
+
+named!(wal_header<(WalHeader, Checksum)>, do_parse!(
+    magic: le_u64 >>
+    page_size: page_size >>
+    epoch: le_u64 >>
+    salt: salt >>
+    checksum: checksum >>
+    (WalHeader {
+        magic: magic,
+        page_size: page_size,
+        epoch: epoch,
+        salt: salt,
+    }, checksum)
+));
+
+named!(frame_header<(FrameHeader, Checksum)>, do_parse!(
+    page_num: le_u32 >>
+    commit: le_u32 >>
+    epoch: le_u64 >>
+    salt: salt >>
+    checksum: checksum >>
+    (FrameHeader {
+        page_num: page_num,
+        commit: commit,
+        epoch: epoch,
+        salt: salt,
+    }, checksum)
+));
+
+/// Runs `parser` on the start of `buf`, failing if `buf` is too short
+/// or holds something invalid
+fn decode<'a, T, F>(parser: F, buf: &'a [u8], what: &str) -> Decoded<T>
+    where F: Fn(&'a [u8]) -> IResult<&'a [u8], T>
+{
+    match parser(buf) {
+        IResult::Done(_, t) => Ok(t),
+        IResult::Incomplete(needed) => Err(Invalid {
+            offset: buf.len() as u64,
+            expected: match needed {
+                Needed::Size(n) => format!("a {} of {} bytes", what, n),
+                Needed::Unknown => format!("a complete {}", what),
+            },
+            found: format!("{} bytes", buf.len()),
+        }),
+        IResult::Error(_) => Err(Invalid {
+            offset: 0,
+            expected: format!("a valid {}", what),
+            found: "bytes that don't parse as one".to_string(),
+        }),
+    }
+}
+
+impl Invalid {
+    /// Places the problem in `file`, where the bytes decoded start at
+    /// `offset`
+    pub fn at(self, file: &Path, offset: u64, frame: Option<FrameNum>,
+              page: Option<PageNum>) -> Corruption {
+        Corruption {
+            file: file.to_path_buf(),
+            offset: offset + self.offset,
+            frame: frame,
+            page: page,
+            expected: self.expected,
+            found: self.found,
+        }
+    }
+}
+
+impl fmt::Display for Invalid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "at offset {}: expected {}, found {}", self.offset, self.expected, self.found)
+    }
+}
+
+impl error::Error for Invalid {
+    fn description(&self) -> &str {
+        "invalid data"
+    }
+}
+
+impl DbHeader {
+    /// The checksummed portion of the header
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(DB_HEADER_DATA_SIZE as usize);
+        buf.write_u64::<LittleEndian>(self.magic).expect("vec");
+        buf.write_u32::<LittleEndian>(self.page_size.to_u32()).expect("vec");
+        buf.write_u32::<LittleEndian>(self.version).expect("vec");
+        buf.write_u32::<LittleEndian>(self.page_count).expect("vec");
+        buf.write_u32::<LittleEndian>(self.freelist_trunk).expect("vec");
+        buf.write_u32::<LittleEndian>(self.freelist_count).expect("vec");
+        buf.write_u32::<LittleEndian>(self.change_counter).expect("vec");
+        buf.write_u32::<LittleEndian>(self.schema_root).expect("vec");
+        buf.write_u32::<LittleEndian>(self.application_id).expect("vec");
+        buf.write_u32::<LittleEndian>(self.user_version).expect("vec");
+        buf
+    }
+
+    pub fn checksum(&self) -> Checksum {
+        Checksum::default().update(&self.to_bytes())
+    }
+
+    /// The header as stored, followed by its checksum
+    pub fn to_checksummed_bytes(&self) -> Vec<u8> {
+        let mut buf = self.to_bytes();
+        let checksum = self.checksum();
+        buf.write_u32::<LittleEndian>(checksum.0).expect("vec");
+        buf.write_u32::<LittleEndian>(checksum.1).expect("vec");
+        buf
+    }
+
+    /// Decodes a header and the checksum stored after it, leaving it
+    /// to the caller to verify, since the copy in the database file
+    /// may be torn while a good one is in the log
+    pub fn decode(buf: &[u8]) -> Decoded<(DbHeader, Checksum)> {
+        decode(db_header, buf, "database header")
+    }
+}
+
+impl Trunk {
+    /// The most leaves a trunk page can list
+    pub fn capacity(page_size: PageSize) -> usize {
+        ((page_size.to_u32() - TRUNK_HEADER_SIZE) / 4) as usize
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(TRUNK_HEADER_SIZE as usize + self.leaves.len() * 4);
+        buf.write_u32::<LittleEndian>(self.next).expect("vec");
+        buf.write_u32::<LittleEndian>(self.leaves.len() as u32).expect("vec");
+        for &leaf in &self.leaves {
+            buf.write_u32::<LittleEndian>(leaf).expect("vec");
+        }
+        buf
+    }
+
+    /// Decodes a whole trunk page, failing if it claims more leaves
+    /// than fit in it
+    pub fn decode(page: &[u8]) -> Decoded<Trunk> {
+        let (next, n) = decode(trunk_header, page, "freelist trunk")?;
+        let room = (page.len() - TRUNK_HEADER_SIZE as usize) / 4;
+        if n as usize > room {
+            return Err(Invalid {
+                offset: 4,
+                expected: format!("at most {} leaves", room),
+                found: format!("{} leaves", n),
+            });
+        }
+        // The leaves fit, so this can't fail
+        let leaves = decode(|buf| trunk_leaves(buf, n), &page[TRUNK_HEADER_SIZE as usize..],
+                            "freelist trunk")?;
+        Ok(Trunk { next: next, leaves: leaves })
+    }
+}
+
+impl WalHeader {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(WAL_HEADER_DATA_SIZE as usize);
+        buf.write_u64::<LittleEndian>(self.magic).expect("vec");
+        buf.write_u32::<LittleEndian>(self.page_size.to_u32()).expect("vec");
+        buf.write_u64::<LittleEndian>(self.epoch).expect("vec");
+        buf.write_u32::<LittleEndian>(self.salt.0).expect("vec");
+        buf.write_u32::<LittleEndian>(self.salt.1).expect("vec");
+        buf
+    }
+
+    /// The checksum of the header, which also seeds the checksum of
+    /// the first frame.
+    pub fn checksum(&self) -> Checksum {
+        Checksum::default().update(&self.to_bytes())
+    }
+
+    /// The header as stored, followed by its checksum
+    pub fn to_checksummed_bytes(&self) -> Vec<u8> {
+        let mut buf = self.to_bytes();
+        let checksum = self.checksum();
+        buf.write_u32::<LittleEndian>(checksum.0).expect("vec");
+        buf.write_u32::<LittleEndian>(checksum.1).expect("vec");
+        buf
+    }
+
+    /// Decodes a header written by `to_checksummed_bytes`, failing if
+    /// it doesn't match its checksum
+    pub fn decode(buf: &[u8]) -> Decoded<WalHeader> {
+        let (header, stored) = decode(wal_header, buf, "wal header")?;
+        if stored != header.checksum() {
+            return Err(Invalid {
+                offset: WAL_HEADER_DATA_SIZE as u64,
+                expected: format!("header checksum {}", header.checksum()),
+                found: format!("{}", stored),
+            });
+        }
+        Ok(header)
+    }
+}
+
+impl FrameHeader {
+    /// The checksummed portion of the header
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(FRAME_HEADER_DATA_SIZE as usize);
+        buf.write_u32::<LittleEndian>(self.page_num).expect("vec");
+        buf.write_u32::<LittleEndian>(self.commit).expect("vec");
+        buf.write_u64::<LittleEndian>(self.epoch).expect("vec");
+        buf.write_u32::<LittleEndian>(self.salt.0).expect("vec");
+        buf.write_u32::<LittleEndian>(self.salt.1).expect("vec");
+        buf
+    }
+
+    /// Decodes a header and the checksum stored after it. The
+    /// checksum covers every frame before this one too, so only the
+    /// caller can verify it.
+    pub fn decode(buf: &[u8]) -> Decoded<(FrameHeader, Checksum)> {
+        decode(frame_header, buf, "frame header")
+    }
+}
+
+impl fmt::Display for Salt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:08x}:{:08x}", self.0, self.1)
+    }
+}
+
+impl Salt {
+    pub fn random() -> Salt {
+        Salt(rand::random(), rand::random())
+    }
+
+    pub fn of(h: &IndexHeader) -> Salt {
+        Salt(h.salt[0], h.salt[1])
+    }
+}
@@ -0,0 +1,39 @@
+//! Reports of damage to the database files
+//!
+//! Damage found while running transactions comes back as
+//! `ErrorKind::Corrupt`, carrying a `Corruption` that says where it
+//! is. To look for damage without waiting to trip over it,
+//! `Wabl::check_integrity` scans the database file, the log and the
+//! Wal index, and lists every problem rather than stopping at the
+//! first.
+
+use wal::{PageNum, FrameNum};
+use std::fmt;
+use std::path::PathBuf;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Corruption {
+    /// The damaged file: the `.db`, the `.wal` or the `.shm`
+    pub file: PathBuf,
+    /// Where in the file the damage starts
+    pub offset: u64,
+    /// The frame of the log involved, if any
+    pub frame: Option<FrameNum>,
+    /// The page involved, if known
+    pub page: Option<PageNum>,
+    pub expected: String,
+    pub found: String,
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} is corrupt at offset {}", self.file.display(), self.offset)?;
+        if let Some(frame) = self.frame {
+            write!(f, ", frame {}", frame)?;
+        }
+        if let Some(page) = self.page {
+            write!(f, ", page {}", page)?;
+        }
+        write!(f, ": expected {}, found {}", self.expected, self.found)
+    }
+}
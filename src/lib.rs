@@ -9,12 +9,77 @@ extern crate scopeguard;
 #[macro_use]
 extern crate nom;
 extern crate byteorder;
+extern crate rand;
+extern crate libc;
 
 pub mod errors {
+    use integrity::Corruption;
+    use wal::PageNum;
+
     error_chain! {
         foreign_links {
             Io(::std::io::Error);
         }
+
+        errors {
+            Busy {
+                description("database is busy")
+                display("timed out waiting for another connection")
+            }
+            BusySnapshot {
+                description("snapshot is out of date")
+                display("another transaction changed the database since this snapshot")
+            }
+            Corrupt(report: Corruption) {
+                description("database is corrupt")
+                display("{}", report)
+            }
+            NotADatabase {
+                description("file is not a database")
+                display("file is not a database")
+            }
+            UnsupportedVersion(version: u32) {
+                description("unsupported database format version")
+                display("database format version {} is not supported", version)
+            }
+            PageSizeMismatch(expected: u32, found: u32) {
+                description("page size mismatch")
+                display("page size is {} bytes, expected {}", found, expected)
+            }
+            PageOutOfRange(page: PageNum, db_size: PageNum) {
+                description("page is past the end of the database")
+                display("page {} is past the end of the database of {} pages", page, db_size)
+            }
+            /// A `Wal` transaction wrote pages without learning the
+            /// size of the database
+            UnknownDbSize {
+                description("database size is unknown")
+                display("the database size must be set before committing")
+            }
+            /// Page 0 holds the database header, which only `Wabl`
+            /// itself may touch
+            ReservedPage(page: PageNum) {
+                description("page is reserved")
+                display("page {} holds the database header", page)
+            }
+            /// Shrinking the database would drop a page that is on
+            /// the freelist
+            FreePage(page: PageNum) {
+                description("page is on the freelist")
+                display("page {} is on the freelist", page)
+            }
+            /// The storage can't be written
+            ReadOnly {
+                description("database is read-only")
+                display("attempt to write a read-only database")
+            }
+            /// The storage is out of space, or the database has as
+            /// many pages as it can
+            Full {
+                description("database or disk is full")
+                display("database or disk is full")
+            }
+        }
     }
 }
 
@@ -26,3 +91,9 @@ pub mod lock;
 pub mod page;
 pub mod page_store;
 pub mod btree;
+pub mod checksum;
+pub mod format;
+pub mod integrity;
+pub mod vfs;
+pub mod mem_vfs;
+pub mod fault_vfs;
@@ -1,35 +1,302 @@
 use errors::*;
-use fs2::FileExt;
-use std::rc::Rc;
-use std::cell::RefCell;
-use std::fs::File;
+use vfs::{VfsFile, Shm};
+use std::fs::{self, File, OpenOptions};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::collections::HashMap;
+use std::sync::{Arc, Weak, Mutex};
+use std::path::{Path, PathBuf};
+use std::os::unix::io::AsRawFd;
+use std::io;
+use std::mem;
+use libc;
 
-pub struct ExLock(Rc<RefCell<File>>);
+pub struct ExLock(Arc<VfsFile>);
 
-pub struct ShLock(Rc<RefCell<File>>);
+/// A file shared by every connection in the process, with one-byte
+/// fcntl locks at chosen offsets.
+///
+/// fcntl locks belong to the process, not the file descriptor, so
+/// they don't keep connections in the same process apart, and closing
+/// any descriptor for the file releases all of them. So the file is
+/// only opened once per process, and the connections' locks are
+/// tracked here, taking the fcntl lock when the first connection
+/// locks a byte and releasing it after the last. Locks held by a
+/// process that dies are released by the OS.
+#[derive(Clone)]
+pub struct LockFile(Arc<Inode>);
+
+struct Inode {
+    file: File,
+    /// The locks held in this process, by offset
+    locks: Mutex<HashMap<u64, ByteLocks>>,
+}
+
+#[derive(Default)]
+struct ByteLocks {
+    shared: u32,
+    exclusive: bool,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LockKind {
+    Shared,
+    Exclusive,
+}
+
+/// A lock on one byte of a `Shm`, released on drop
+pub struct ByteLock {
+    shm: Arc<Shm>,
+    offset: u64,
+    kind: LockKind,
+}
+
+/// The `LockFile`s open in this process, by path
+static LOCK_FILES: Mutex<Option<HashMap<PathBuf, Weak<Inode>>>> = Mutex::new(None);
+
+/// What to do when a lock is held by someone else
+#[derive(Clone)]
+pub enum BusyHandler {
+    /// Wait as long as it takes
+    Block,
+    /// Retry with exponential backoff, giving up with
+    /// `ErrorKind::Busy` once the timeout has passed
+    Timeout(Duration),
+    /// Called with the number of retries so far. Retry if it returns
+    /// true, otherwise give up with `ErrorKind::Busy`. The callback
+    /// can sleep before returning.
+    Callback(Arc<Fn(u32) -> bool + Send + Sync>),
+}
+
+/// The state of one attempt to get a busy resource
+pub struct Busy<'a> {
+    handler: &'a BusyHandler,
+    retries: u32,
+    start: Instant,
+}
+
+/// The first backoff delay
+const BACKOFF_MIN_MS: u64 = 1;
+/// The longest backoff delay
+const BACKOFF_MAX_MS: u64 = 100;
+
+impl BusyHandler {
+    pub fn start(&self) -> Busy<'_> {
+        Busy {
+            handler: self,
+            retries: 0,
+            start: Instant::now(),
+        }
+    }
+}
+
+impl<'a> Busy<'a> {
+    /// Waits before the next retry, or fails with `ErrorKind::Busy`
+    /// if the handler gives up
+    pub fn wait(&mut self) -> Result<()> {
+        let backoff = BACKOFF_MIN_MS << self.retries.min(7);
+        let backoff = Duration::from_millis(backoff.min(BACKOFF_MAX_MS));
+        match *self.handler {
+            BusyHandler::Block => thread::sleep(backoff),
+            BusyHandler::Timeout(timeout) => {
+                let elapsed = self.start.elapsed();
+                if elapsed >= timeout {
+                    bail!(ErrorKind::Busy);
+                }
+                thread::sleep(backoff.min(timeout - elapsed));
+            }
+            BusyHandler::Callback(ref f) => {
+                if !f(self.retries) {
+                    bail!(ErrorKind::Busy);
+                }
+            }
+        }
+        self.retries += 1;
+        Ok(())
+    }
+}
 
 impl ExLock {
-    pub fn new(file: Rc<RefCell<File>>) -> Result<ExLock> {
-        file.borrow().lock_exclusive()?;
-        Ok(ExLock(file))
+    /// Takes the lock, consulting `busy` while it is held elsewhere
+    pub fn new(file: Arc<VfsFile>, busy: &BusyHandler) -> Result<ExLock> {
+        let mut busy = busy.start();
+        loop {
+            if file.try_lock(LockKind::Exclusive)? {
+                return Ok(ExLock(file));
+            }
+            busy.wait()?;
+        }
     }
 }
 
 impl Drop for ExLock {
     fn drop(&mut self) {
-        self.0.borrow().unlock().expect("unlock");
+        self.0.unlock().expect("unlock");
+    }
+}
+
+impl LockFile {
+    /// Opens the file at `p`, creating it if needed, or returns the
+    /// already open one
+    pub fn open(p: &Path) -> Result<LockFile> {
+        let dir = match p.parent() {
+            Some(d) if d != Path::new("") => d,
+            _ => Path::new("."),
+        };
+        let name = match p.file_name() {
+            Some(n) => n,
+            None => bail!("lock file path has no file name"),
+        };
+        let path = fs::canonicalize(dir)?.join(name);
+
+        let mut files = LOCK_FILES.lock().expect("lock files");
+        let files = files.get_or_insert_with(HashMap::new);
+        if let Some(inode) = files.get(&path).and_then(Weak::upgrade) {
+            return Ok(LockFile(inode));
+        }
+
+        let file = OpenOptions::new()
+            .read(true).write(true).create(true).truncate(false)
+            .open(&path)?;
+        let inode = Arc::new(Inode {
+            file: file,
+            locks: Mutex::new(HashMap::new()),
+        });
+        files.retain(|_, f| f.upgrade().is_some());
+        files.insert(path, Arc::downgrade(&inode));
+
+        Ok(LockFile(inode))
+    }
+
+    pub fn file(&self) -> &File {
+        &self.0.file
+    }
+
+    /// Returns false instead of waiting if the byte is locked
+    /// incompatibly, by this process or another
+    pub fn try_lock(&self, offset: u64, kind: LockKind) -> Result<bool> {
+        let mut locks = self.0.locks.lock().expect("byte locks");
+        let held = locks.entry(offset).or_default();
+
+        match kind {
+            LockKind::Shared => {
+                if held.exclusive {
+                    return Ok(false);
+                }
+                if held.shared == 0 && !fcntl_lock(&self.0.file, offset, libc::F_RDLCK)? {
+                    return Ok(false);
+                }
+                held.shared += 1;
+            }
+            LockKind::Exclusive => {
+                if held.exclusive || held.shared != 0 {
+                    return Ok(false);
+                }
+                if !fcntl_lock(&self.0.file, offset, libc::F_WRLCK)? {
+                    return Ok(false);
+                }
+                held.exclusive = true;
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Turns an exclusive lock into a shared one
+    pub fn downgrade(&self, offset: u64) -> Result<()> {
+        let mut locks = self.0.locks.lock().expect("byte locks");
+        let held = locks.get_mut(&offset).expect("byte lock");
+        fcntl_lock(&self.0.file, offset, libc::F_RDLCK)?;
+        held.exclusive = false;
+        held.shared = 1;
+
+        Ok(())
+    }
+
+    /// Releases a lock taken by `try_lock`
+    pub fn unlock(&self, offset: u64, kind: LockKind) -> Result<()> {
+        let mut locks = self.0.locks.lock().expect("byte locks");
+        let released = {
+            let held = locks.get_mut(&offset).expect("byte lock");
+            match kind {
+                LockKind::Shared => held.shared -= 1,
+                LockKind::Exclusive => held.exclusive = false,
+            }
+            held.shared == 0 && !held.exclusive
+        };
+        if released {
+            locks.remove(&offset);
+            fcntl_lock(&self.0.file, offset, libc::F_UNLCK)?;
+        }
+
+        Ok(())
     }
 }
 
-impl ShLock {
-    pub fn new(file: Rc<RefCell<File>>) -> Result<ShLock> {
-        file.borrow().lock_shared()?;
-        Ok(ShLock(file))
+impl ByteLock {
+    /// Returns `None` instead of waiting if the byte is locked
+    /// incompatibly
+    pub fn try_new(shm: &Arc<Shm>, offset: u64, kind: LockKind) -> Result<Option<ByteLock>> {
+        if !shm.try_lock(offset, kind)? {
+            return Ok(None);
+        }
+
+        Ok(Some(ByteLock {
+            shm: shm.clone(),
+            offset: offset,
+            kind: kind,
+        }))
+    }
+
+    /// Takes the lock, consulting `busy` while it is held elsewhere
+    pub fn new(shm: &Arc<Shm>, offset: u64, kind: LockKind,
+               busy: &BusyHandler) -> Result<ByteLock> {
+        let mut busy = busy.start();
+        loop {
+            if let Some(lock) = ByteLock::try_new(shm, offset, kind)? {
+                return Ok(lock);
+            }
+            busy.wait()?;
+        }
+    }
+
+    /// Turns an exclusive lock into a shared one, without letting
+    /// anyone else take it exclusively in between
+    pub fn downgrade(&mut self) -> Result<()> {
+        if self.kind == LockKind::Shared {
+            return Ok(());
+        }
+
+        self.shm.downgrade(self.offset)?;
+        self.kind = LockKind::Shared;
+
+        Ok(())
     }
 }
 
-impl Drop for ShLock {
+impl Drop for ByteLock {
     fn drop(&mut self) {
-        self.0.borrow().unlock().expect("unlock");
+        self.shm.unlock(self.offset, self.kind).expect("unlock");
+    }
+}
+
+/// Sets the fcntl lock on the byte at `offset` without waiting.
+/// Returns false if another process holds an incompatible lock.
+fn fcntl_lock(file: &File, offset: u64, kind: libc::c_int) -> Result<bool> {
+    let mut fl: libc::flock = unsafe { mem::zeroed() };
+    fl.l_type = kind as libc::c_short;
+    fl.l_whence = libc::SEEK_SET as libc::c_short;
+    fl.l_start = offset as libc::off_t;
+    fl.l_len = 1;
+
+    let r = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETLK, &fl) };
+    if r == -1 {
+        let e = io::Error::last_os_error();
+        match e.raw_os_error() {
+            Some(libc::EAGAIN) | Some(libc::EACCES) => return Ok(false),
+            _ => return Err(e.into()),
+        }
     }
+
+    Ok(true)
 }
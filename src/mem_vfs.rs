@@ -0,0 +1,240 @@
+//! A `Vfs` that keeps everything in memory
+//!
+//! Files and shared memory live as long as the `MemVfs` they were
+//! created in. Clones of a `MemVfs` share their files, so any number
+//! of connections can open the same in-memory database, but nothing
+//! is visible to other processes.
+
+use errors::*;
+use lock::LockKind;
+use vfs::{Vfs, VfsFile, Shm, write_error};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::atomic::AtomicU64;
+use std::io;
+use libc;
+
+/// The largest a file can grow. Anything beyond fails with `EFBIG`,
+/// rather than exhausting memory, e.g. when a corrupt database asks
+/// for an absurd size.
+pub const MAX_FILE_SIZE: u64 = 1 << 30;
+
+#[derive(Clone, Default)]
+pub struct MemVfs(Arc<Mutex<MemFs>>);
+
+#[derive(Default)]
+struct MemFs {
+    files: HashMap<PathBuf, Arc<Inode>>,
+    shms: HashMap<PathBuf, Arc<ShmInode>>,
+}
+
+#[derive(Default)]
+struct Inode {
+    data: Mutex<Vec<u8>>,
+    locks: Mutex<Locks>,
+    /// Signalled when a lock is released
+    unlocked: Condvar,
+}
+
+/// The locks held on a file or a shared memory byte
+#[derive(Default)]
+struct Locks {
+    shared: u32,
+    exclusive: bool,
+}
+
+/// An open file. Like a flock, its lock belongs to the handle.
+struct MemFile {
+    inode: Arc<Inode>,
+    held: Mutex<Option<LockKind>>,
+}
+
+#[derive(Default)]
+struct ShmInode {
+    /// Made of atomics so they are aligned for the index and can be
+    /// written through shared pointers
+    regions: Mutex<Vec<Box<[AtomicU64]>>>,
+    locks: Mutex<HashMap<u64, Locks>>,
+}
+
+struct MemShm(Arc<ShmInode>);
+
+impl MemVfs {
+    pub fn new() -> MemVfs {
+        MemVfs::default()
+    }
+}
+
+impl Vfs for MemVfs {
+    fn open(&self, path: &Path) -> Result<Box<VfsFile>> {
+        let mut fs = self.0.lock().expect("mem fs");
+        let inode = fs.files.entry(path.to_path_buf())
+            .or_default()
+            .clone();
+        Ok(Box::new(MemFile {
+            inode: inode,
+            held: Mutex::new(None),
+        }))
+    }
+
+    fn open_shm(&self, path: &Path) -> Result<Box<Shm>> {
+        let mut fs = self.0.lock().expect("mem fs");
+        let inode = fs.shms.entry(path.to_path_buf())
+            .or_default()
+            .clone();
+        Ok(Box::new(MemShm(inode)))
+    }
+
+    fn sync_dir(&self, path: &Path) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Locks {
+    /// Returns false if the lock is held incompatibly
+    fn try_lock(&mut self, kind: LockKind) -> bool {
+        match kind {
+            LockKind::Shared if !self.exclusive => self.shared += 1,
+            LockKind::Exclusive if !self.exclusive && self.shared == 0 => {
+                self.exclusive = true;
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn unlock(&mut self, kind: LockKind) {
+        match kind {
+            LockKind::Shared => self.shared -= 1,
+            LockKind::Exclusive => self.exclusive = false,
+        }
+    }
+}
+
+impl VfsFile for MemFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        let data = self.inode.data.lock().expect("mem file");
+        let start = offset as usize;
+        if start + buf.len() > data.len() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                      "failed to fill whole buffer").into());
+        }
+        buf.copy_from_slice(&data[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> Result<()> {
+        let mut data = self.inode.data.lock().expect("mem file");
+        let end = offset.saturating_add(buf.len() as u64);
+        if (data.len() as u64) < end {
+            resize(&mut data, end)?;
+        }
+        let start = offset as usize;
+        data[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn len(&self) -> Result<u64> {
+        Ok(self.inode.data.lock().expect("mem file").len() as u64)
+    }
+
+    fn set_len(&self, len: u64) -> Result<()> {
+        resize(&mut self.inode.data.lock().expect("mem file"), len)
+    }
+
+    fn allocate(&self, len: u64) -> Result<()> {
+        let mut data = self.inode.data.lock().expect("mem file");
+        if (data.len() as u64) < len {
+            resize(&mut data, len)?;
+        }
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn lock(&self, kind: LockKind) -> Result<()> {
+        let mut held = self.held.lock().expect("held lock");
+        let mut locks = self.inode.locks.lock().expect("file locks");
+        release(&mut held, &mut locks);
+        while !locks.try_lock(kind) {
+            locks = self.inode.unlocked.wait(locks).expect("file locks");
+        }
+        *held = Some(kind);
+        Ok(())
+    }
+
+    fn try_lock(&self, kind: LockKind) -> Result<bool> {
+        let mut held = self.held.lock().expect("held lock");
+        let mut locks = self.inode.locks.lock().expect("file locks");
+        release(&mut held, &mut locks);
+        let locked = locks.try_lock(kind);
+        if locked {
+            *held = Some(kind);
+        }
+        self.inode.unlocked.notify_all();
+        Ok(locked)
+    }
+
+    fn unlock(&self) -> Result<()> {
+        let mut held = self.held.lock().expect("held lock");
+        let mut locks = self.inode.locks.lock().expect("file locks");
+        release(&mut held, &mut locks);
+        self.inode.unlocked.notify_all();
+        Ok(())
+    }
+}
+
+impl Drop for MemFile {
+    fn drop(&mut self) {
+        self.unlock().expect("unlock");
+    }
+}
+
+fn resize(data: &mut Vec<u8>, len: u64) -> Result<()> {
+    if len > MAX_FILE_SIZE {
+        return write_error(Err(io::Error::from_raw_os_error(libc::EFBIG)));
+    }
+    data.resize(len as usize, 0);
+    Ok(())
+}
+
+/// Drops a handle's lock, since like a flock, relocking replaces it
+fn release(held: &mut Option<LockKind>, locks: &mut Locks) {
+    if let Some(kind) = held.take() {
+        locks.unlock(kind);
+    }
+}
+
+impl Shm for MemShm {
+    fn region(&self, n: u32, size: usize) -> Result<*mut u8> {
+        let mut regions = self.0.regions.lock().expect("regions");
+        while regions.len() <= n as usize {
+            let words = size.div_ceil(8);
+            regions.push((0..words).map(|_| AtomicU64::new(0)).collect());
+        }
+
+        Ok(regions[n as usize].as_ptr() as *mut u8)
+    }
+
+    fn try_lock(&self, offset: u64, kind: LockKind) -> Result<bool> {
+        let mut locks = self.0.locks.lock().expect("byte locks");
+        Ok(locks.entry(offset).or_default().try_lock(kind))
+    }
+
+    fn downgrade(&self, offset: u64) -> Result<()> {
+        let mut locks = self.0.locks.lock().expect("byte locks");
+        let held = locks.get_mut(&offset).expect("byte lock");
+        held.exclusive = false;
+        held.shared = 1;
+        Ok(())
+    }
+
+    fn unlock(&self, offset: u64, kind: LockKind) -> Result<()> {
+        let mut locks = self.0.locks.lock().expect("byte locks");
+        locks.get_mut(&offset).expect("byte lock").unlock(kind);
+        Ok(())
+    }
+}
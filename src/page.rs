@@ -1,6 +1,7 @@
 use units::PageSize;
 use std::iter;
 
+#[derive(Clone)]
 pub struct Page(Vec<u8>);
 
 impl Page {
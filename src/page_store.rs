@@ -1,126 +1,222 @@
-use fs2::FileExt;
 use page::Page;
 use wal::PageNum;
-use std::path::Path;
-use lock::ExLock;
+use std::path::{Path, PathBuf};
+use lock::{ExLock, BusyHandler};
 use errors::*;
 use units::PageSize;
-use std::rc::Rc;
-use std::cell::RefCell;
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Write, Seek, SeekFrom, BufReader, BufWriter};
-use byteorder::*;
+use std::sync::Arc;
+use vfs::{Vfs, VfsFile};
+use format::*;
+use integrity::Corruption;
 
 pub struct PageStore {
-    file: Rc<RefCell<File>>,
+    file: Arc<VfsFile>,
+    path: PathBuf,
     page_size: PageSize,
 }
 
 const MAGIC: u64 = 0xee2e85c62ff153c8;
-const HEADER_SIZE: u32 = 100;
-
-struct Header {
-    magic: u64,
-    page_size: PageSize,
-}
 
 impl PageStore {
-    pub fn new<P: AsRef<Path>>(p: &P, page_size: PageSize) -> Result<PageStore> {
-        let file = OpenOptions::new()
-            .read(true).write(true).create(true)
-            .open(p.as_ref().with_extension("db"))?;
+    /// Opens the database file, consulting `busy` if another
+    /// connection is creating it
+    pub fn new<P: AsRef<Path>>(vfs: &Vfs, p: &P, page_size: PageSize,
+                               busy: &BusyHandler) -> Result<PageStore> {
+        let path = p.as_ref().with_extension("db");
+        let file = vfs.open(&path)?;
 
         let mut page_store = PageStore {
-            file: Rc::new(RefCell::new(file)),
+            file: Arc::from(file),
+            path: path,
             page_size: page_size,
         };
 
-        page_store.init(page_size)?;
+        page_store.init(page_size, busy)?;
 
         Ok(page_store)
     }
 
-    fn init(&mut self, page_size: PageSize) -> Result<()> {
-        let lock = ExLock::new(self.file.clone())?;
+    /// Creates page 0 of a new database, or checks the parts of the
+    /// header that never change. The rest may be newer in the log, so
+    /// `Wabl` checks it.
+    fn init(&mut self, page_size: PageSize, busy: &BusyHandler) -> Result<()> {
+        let lock = ExLock::new(self.file.clone(), busy)?;
         if let Some(h) = self.read_header(&lock)? {
-            if h.magic != MAGIC {
-                bail!("bad magic");
+            if h.version == 0 || h.version > DB_VERSION {
+                bail!(ErrorKind::UnsupportedVersion(h.version));
+            }
+            if h.page_size != page_size {
+                bail!(ErrorKind::PageSizeMismatch(page_size.to_u32(), h.page_size.to_u32()));
+            }
+            // Page 0 may have been cut short by a crash while
+            // creating the database
+            if self.file.len()? < page_size.to_u32() as u64 {
+                self.file.allocate(page_size.to_u32() as u64)?;
             }
         } else {
-            let header = Header {
+            let header = DbHeader {
                 magic: MAGIC,
                 page_size: page_size,
+                version: DB_VERSION,
+                page_count: 1,
+                freelist_trunk: 0,
+                freelist_count: 0,
+                change_counter: 0,
+                schema_root: 0,
+                application_id: 0,
+                user_version: 0,
             };
             self.write_header(header, &lock)?;
+            // Before any page can be written after it
+            self.file.sync()?;
         }
         Ok(())
     }
 
-    fn read_header(&mut self, lock: &ExLock) -> Result<Option<Header>> {
-        let mut file = self.file.borrow_mut();
-
-        if file.metadata()?.len() == 0 {
+    fn read_header(&mut self, lock: &ExLock) -> Result<Option<DbHeader>> {
+        let len = self.file.len()?;
+        if len == 0 {
             return Ok(None);
         }
 
-        file.seek(SeekFrom::Start(0))?;
-        let mut rdr = BufReader::with_capacity(HEADER_SIZE as usize, &mut *file);
-
-        let magic = rdr.read_u64::<LittleEndian>()?;
-        let page_size = rdr.read_u32::<LittleEndian>()?;
+        // Nothing but zeros means a crash while creating the
+        // database, before the header reached the disk
+        if len <= self.page_size.to_u32() as u64 {
+            let mut buf = vec![0; len as usize];
+            self.file.read_at(&mut buf, 0)?;
+            if buf.iter().all(|&b| b == 0) {
+                return Ok(None);
+            }
+        }
 
-        let header = Header {
-            magic: MAGIC,
-            page_size: PageSize::new(page_size),
-        };
+        // Let the decoder report a file too short for the header
+        let mut buf = vec![0; len.min(DB_HEADER_SIZE as u64) as usize];
+        self.file.read_at(&mut buf, 0)?;
+        let (header, _) = DbHeader::decode(&buf).chain_err(|| ErrorKind::NotADatabase)?;
+        if header.magic != MAGIC {
+            bail!(ErrorKind::NotADatabase);
+        }
 
         Ok(Some(header))
     }
 
-    fn write_header(&mut self, h: Header, lock: &ExLock) -> Result<()> {
-        let mut file = self.file.borrow_mut();
-        file.seek(SeekFrom::Start(0))?;
-        let mut wtr = BufWriter::with_capacity(HEADER_SIZE as usize, &mut *file);
-        wtr.write_u64::<LittleEndian>(h.magic)?;
-        wtr.write_u32::<LittleEndian>(h.page_size.to_u32())?;
-        if wtr.into_inner().is_err() {
-            bail!("writing PageStore header")
-        }
+    /// Writes page 0, holding `h`
+    fn write_header(&mut self, h: DbHeader, lock: &ExLock) -> Result<()> {
+        let mut page = Page::new(self.page_size);
+        let bytes = h.to_checksummed_bytes();
+        page.buf_mut()[..bytes.len()].copy_from_slice(&bytes);
+        self.write_page(0, page)
+    }
 
-        Ok(())
+    /// The path of the database file
+    pub fn path(&self) -> &Path {
+        &self.path
     }
 
-    fn seek_to_page(&mut self, n: PageNum) -> Result<()> {
-        let offset = self.page_size.to_u32() as u64 * n as u64;
-        let mut file = self.file.borrow_mut();
-        file.seek(SeekFrom::Start(offset))?;
-        Ok(())
+    pub fn page_size(&self) -> PageSize {
+        self.page_size
+    }
+
+    pub fn page_offset(&self, n: PageNum) -> u64 {
+        self.page_size.to_u32() as u64 * n as u64
     }
 
-    pub fn read_page(&mut self, n: PageNum) -> Result<Page> {
-        self.seek_to_page(n)?;
+    /// Pages past the end of the file read as zeros. So does the rest
+    /// of a page cut short, e.g. page 0 of a new database, which holds
+    /// only the header.
+    pub fn read_page(&self, n: PageNum) -> Result<Page> {
         let mut page = Page::new(self.page_size);
-        let mut file = self.file.borrow_mut();
-        file.read_exact(page.buf_mut())?;
+        let offset = self.page_offset(n);
+        let len = self.file.len()?;
+        if offset >= len {
+            return Ok(page);
+        }
+
+        let available = (len - offset).min(self.page_size.to_u32() as u64) as usize;
+        self.file.read_at(&mut page.buf_mut()[..available], offset)?;
         Ok(page)
     }
 
-    pub fn write_page(&mut self, n: PageNum, p: Page) -> Result<()> {
-        self.seek_to_page(n)?;
-        let mut file = self.file.borrow_mut();
-        file.write_all(p.buf())?;
+    pub fn write_page(&self, n: PageNum, p: Page) -> Result<()> {
+        self.file.write_at(p.buf(), self.page_offset(n))?;
         Ok(())
     }
 
-    pub fn resize_at_least(&mut self, n: PageNum) -> Result<()> {
+    pub fn resize_at_least(&self, n: PageNum) -> Result<()> {
         let min_len = n as u64 * self.page_size.to_u32() as u64;
-        let mut file = self.file.borrow_mut();
-        file.allocate(min_len)?;
+        self.file.allocate(min_len)?;
+        Ok(())
+    }
+
+    /// The number of whole pages in the file
+    pub fn num_pages(&self) -> Result<PageNum> {
+        let len = self.file.len()?;
+        Ok((len / self.page_size.to_u32() as u64) as PageNum)
+    }
+
+    /// Drops the pages from `n` on, if the file has any, but never
+    /// page 0
+    pub fn truncate(&self, n: PageNum) -> Result<()> {
+        let max_len = n.max(1) as u64 * self.page_size.to_u32() as u64;
+        if self.file.len()? > max_len {
+            self.file.set_len(max_len)?;
+        }
         Ok(())
     }
 
-    pub fn sync(&mut self) -> Result<()> {
-        self.file.borrow_mut().sync_data()?;
+    /// Checks the header and that the file holds whole pages, listing
+    /// every problem found. Consults `busy` while another connection
+    /// is creating the database.
+    pub fn check_integrity(&self, busy: &BusyHandler) -> Result<Vec<Corruption>> {
+        let lock = ExLock::new(self.file.clone(), busy)?;
+        let mut problems = Vec::new();
+        let corruption = |offset: u64, page: PageNum, expected: String, found: String| Corruption {
+            file: self.path.clone(),
+            offset: offset,
+            frame: None,
+            page: Some(page),
+            expected: expected,
+            found: found,
+        };
+
+        let len = self.file.len()?;
+        let mut buf = vec![0; len.min(DB_HEADER_SIZE as u64) as usize];
+        self.file.read_at(&mut buf, 0)?;
+        match DbHeader::decode(&buf) {
+            Ok((ref h, _)) if h.magic != MAGIC => {
+                problems.push(corruption(0, 0, format!("magic {:016x}", MAGIC),
+                                         format!("{:016x}", h.magic)));
+            }
+            Ok((ref h, _)) if h.page_size != self.page_size => {
+                problems.push(corruption(DB_HEADER_PAGE_SIZE_OFFSET as u64, 0, format!("{} byte pages", self.page_size.to_u32()),
+                                         format!("{} byte pages", h.page_size.to_u32())));
+            }
+            Ok((ref h, _)) if h.version != DB_VERSION => {
+                problems.push(corruption(DB_HEADER_VERSION_OFFSET as u64, 0, format!("version {}", DB_VERSION),
+                                         format!("version {}", h.version)));
+            }
+            Ok((ref h, stored)) if stored != h.checksum() => {
+                problems.push(corruption(DB_HEADER_DATA_SIZE as u64, 0,
+                                         format!("header checksum {}", h.checksum()),
+                                         format!("{}", stored)));
+            }
+            Ok(_) => { }
+            Err(e) => problems.push(e.at(&self.path, 0, None, Some(0))),
+        }
+
+        let page_size = self.page_size.to_u32() as u64;
+        if len % page_size != 0 {
+            let num_pages = (len / page_size) as PageNum;
+            problems.push(corruption(self.page_offset(num_pages), num_pages,
+                                     format!("a page of {} bytes", page_size),
+                                     format!("{} bytes", len % page_size)));
+        }
+
+        Ok(problems)
+    }
+
+    pub fn sync(&self) -> Result<()> {
+        self.file.sync()?;
         Ok(())
     }
 }
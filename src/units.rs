@@ -6,11 +6,19 @@ pub struct PageSize(u32);
 
 impl PageSize {
     pub fn new(page_size: u32) -> PageSize {
-        assert!(page_size >= MIN_PAGE_SIZE);
-        assert!(page_size <= MAX_PAGE_SIZE);
-        assert!(page_size.is_power_of_two());
+        PageSize::try_new(page_size).expect("invalid page size")
+    }
+
+    /// Returns `None` unless `page_size` is a power of two between
+    /// 512 bytes and 1 MB, e.g. when it was read from a corrupt file
+    pub fn try_new(page_size: u32) -> Option<PageSize> {
+        if !(MIN_PAGE_SIZE..=MAX_PAGE_SIZE).contains(&page_size)
+            || !page_size.is_power_of_two()
+        {
+            return None;
+        }
 
-        PageSize(page_size)
+        Some(PageSize(page_size))
     }
 
     pub fn to_u32(&self) -> u32 { self.0 }
@@ -0,0 +1,286 @@
+//! Access to files and shared memory
+//!
+//! Everything the database does to storage goes through a `Vfs`, so
+//! that it can run on something other than the OS filesystem, e.g.
+//! in memory or on an instrumented backend for testing. `OsVfs`, the
+//! default, uses the filesystem directly, and `mem_vfs::MemVfs` needs
+//! no filesystem at all.
+//!
+//! A `Vfs` hands out two kinds of objects. A `VfsFile` holds the
+//! database or the log and is accessed with positional reads and
+//! writes. A `Shm` holds the Wal index: memory shared by every
+//! connection to the database, divided into regions, plus the byte
+//! locks the connections coordinate with.
+
+use errors::*;
+use lock::{LockFile, LockKind};
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+use std::sync::Mutex;
+use std::io;
+use std::os::unix::fs::FileExt as UnixFileExt;
+use std::os::unix::io::AsRawFd;
+use fs2::{FileExt, lock_contended_error};
+use memmap::{Mmap, Protection};
+use libc;
+
+pub trait Vfs: Send + Sync {
+    /// Opens the file at `path` for reading and writing, creating it
+    /// if needed
+    fn open(&self, path: &Path) -> Result<Box<VfsFile>>;
+
+    /// Opens the shared memory named by `path`, creating it if
+    /// needed. Every connection opens its own `Shm`, but all of them
+    /// for the same path share their regions and locks.
+    fn open_shm(&self, path: &Path) -> Result<Box<Shm>>;
+
+    /// Makes the creation and removal of files in the directory
+    /// `path` durable
+    fn sync_dir(&self, path: &Path) -> Result<()>;
+}
+
+pub trait VfsFile: Send + Sync {
+    /// Fills `buf` from `offset`, failing if the file ends first
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()>;
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> Result<()>;
+
+    /// Writes `bufs` one after another from `offset`. Backends that
+    /// can should do it in one operation.
+    fn write_vectored_at(&self, bufs: &[&[u8]], offset: u64) -> Result<()> {
+        let mut offset = offset;
+        for buf in bufs {
+            self.write_at(buf, offset)?;
+            offset += buf.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn len(&self) -> Result<u64>;
+
+    fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Grows or shrinks the file to `len` bytes
+    fn set_len(&self, len: u64) -> Result<()>;
+
+    /// Makes sure the file is at least `len` bytes, reserving the
+    /// space if the backend can
+    fn allocate(&self, len: u64) -> Result<()>;
+
+    /// Makes everything written so far durable
+    fn sync(&self) -> Result<()>;
+
+    /// Locks the whole file, waiting while it is locked incompatibly
+    /// through another `VfsFile`
+    fn lock(&self, kind: LockKind) -> Result<()>;
+
+    /// Returns false instead of waiting if the file is locked
+    /// incompatibly
+    fn try_lock(&self, kind: LockKind) -> Result<bool>;
+
+    fn unlock(&self) -> Result<()>;
+}
+
+pub trait Shm: Send + Sync {
+    /// Returns region `n` of `size` bytes, creating it zeroed if
+    /// needed. Every call for the same region must use the same
+    /// size. The memory stays valid as long as the `Shm`.
+    fn region(&self, n: u32, size: usize) -> Result<*mut u8>;
+
+    /// Locks the byte at `offset`. Returns false instead of waiting
+    /// if it is locked incompatibly through any other `Shm`, in this
+    /// process or another.
+    fn try_lock(&self, offset: u64, kind: LockKind) -> Result<bool>;
+
+    /// Turns this `Shm`'s exclusive lock on `offset` into a shared
+    /// one, without letting anyone else take it exclusively in
+    /// between
+    fn downgrade(&self, offset: u64) -> Result<()>;
+
+    fn unlock(&self, offset: u64, kind: LockKind) -> Result<()>;
+}
+
+/// The operating system's filesystem
+pub struct OsVfs;
+
+struct OsFile(File);
+
+/// A `.shm` file mapped into memory, with fcntl byte locks
+struct OsShm {
+    file: LockFile,
+    regions: Mutex<Vec<Mmap>>,
+}
+
+impl Vfs for OsVfs {
+    fn open(&self, path: &Path) -> Result<Box<VfsFile>> {
+        let file = write_error(OpenOptions::new()
+            .read(true).write(true).create(true).truncate(false)
+            .open(path))?;
+        Ok(Box::new(OsFile(file)))
+    }
+
+    fn open_shm(&self, path: &Path) -> Result<Box<Shm>> {
+        Ok(Box::new(OsShm {
+            file: LockFile::open(path)?,
+            regions: Mutex::new(Vec::new()),
+        }))
+    }
+
+    fn sync_dir(&self, path: &Path) -> Result<()> {
+        File::open(path)?.sync_all()?;
+        Ok(())
+    }
+}
+
+impl VfsFile for OsFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        self.0.read_exact_at(buf, offset)?;
+        Ok(())
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> Result<()> {
+        write_error(self.0.write_all_at(buf, offset))
+    }
+
+    fn write_vectored_at(&self, bufs: &[&[u8]], offset: u64) -> Result<()> {
+        write_error(write_all_vectored_at(&self.0, bufs, offset))
+    }
+
+    fn len(&self) -> Result<u64> {
+        Ok(self.0.metadata()?.len())
+    }
+
+    fn set_len(&self, len: u64) -> Result<()> {
+        write_error(self.0.set_len(len))
+    }
+
+    fn allocate(&self, len: u64) -> Result<()> {
+        write_error(FileExt::allocate(&self.0, len))
+    }
+
+    fn sync(&self) -> Result<()> {
+        write_error(self.0.sync_data())
+    }
+
+    fn lock(&self, kind: LockKind) -> Result<()> {
+        match kind {
+            LockKind::Shared => FileExt::lock_shared(&self.0)?,
+            LockKind::Exclusive => FileExt::lock_exclusive(&self.0)?,
+        }
+        Ok(())
+    }
+
+    fn try_lock(&self, kind: LockKind) -> Result<bool> {
+        let r = match kind {
+            LockKind::Shared => FileExt::try_lock_shared(&self.0),
+            LockKind::Exclusive => FileExt::try_lock_exclusive(&self.0),
+        };
+        match r {
+            Ok(()) => Ok(true),
+            Err(ref e) if e.kind() == lock_contended_error().kind() => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn unlock(&self) -> Result<()> {
+        FileExt::unlock(&self.0)?;
+        Ok(())
+    }
+}
+
+impl Shm for OsShm {
+    fn region(&self, n: u32, size: usize) -> Result<*mut u8> {
+        let mut regions = self.regions.lock().expect("regions");
+        while regions.len() <= n as usize {
+            let offset = (size * regions.len()) as u64;
+            let file = self.file.file();
+            if file.metadata()?.len() < offset + size as u64 {
+                FileExt::allocate(file, offset + size as u64)?;
+            }
+            regions.push(Mmap::open_with_offset(file, Protection::ReadWrite,
+                                                offset as usize, size)?);
+        }
+
+        Ok(regions[n as usize].mut_ptr())
+    }
+
+    fn try_lock(&self, offset: u64, kind: LockKind) -> Result<bool> {
+        self.file.try_lock(offset, kind)
+    }
+
+    fn downgrade(&self, offset: u64) -> Result<()> {
+        self.file.downgrade(offset)
+    }
+
+    fn unlock(&self, offset: u64, kind: LockKind) -> Result<()> {
+        self.file.unlock(offset, kind)
+    }
+}
+
+/// Converts the result of opening or changing a file, so that running
+/// out of space is `ErrorKind::Full` and storage that can't be
+/// written is `ErrorKind::ReadOnly`. Backends should use it for
+/// their own errors too.
+pub fn write_error<T>(r: io::Result<T>) -> Result<T> {
+    let kind = match r {
+        Err(ref e) => match e.raw_os_error() {
+            Some(libc::ENOSPC) | Some(libc::EFBIG) | Some(libc::EDQUOT) => Some(ErrorKind::Full),
+            Some(libc::EROFS) | Some(libc::EACCES) | Some(libc::EPERM) => Some(ErrorKind::ReadOnly),
+            _ => None,
+        },
+        Ok(_) => None,
+    };
+
+    match kind {
+        Some(kind) => r.chain_err(|| kind),
+        None => Ok(r?),
+    }
+}
+
+/// The most buffers to pass to one `pwritev`. Linux allows 1024.
+const MAX_IOVECS: usize = 1024;
+
+/// Writes all of `bufs` to `file` at `offset`, in as few `pwritev`
+/// calls as the system allows
+fn write_all_vectored_at(file: &File, bufs: &[&[u8]], mut offset: u64) -> io::Result<()> {
+    let mut bufs = bufs.to_vec();
+    let mut start = 0;
+    while start < bufs.len() {
+        let end = bufs.len().min(start + MAX_IOVECS);
+        let iovecs = bufs[start..end].iter().map(|b| libc::iovec {
+            iov_base: b.as_ptr() as *mut libc::c_void,
+            iov_len: b.len(),
+        }).collect::<Vec<_>>();
+
+        let r = unsafe {
+            libc::pwritev(file.as_raw_fd(), iovecs.as_ptr(),
+                          iovecs.len() as libc::c_int, offset as libc::off_t)
+        };
+        if r < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+        if r == 0 {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write frames"));
+        }
+
+        // Skip past whatever was written, which may end partway
+        // through a buffer
+        let mut written = r as usize;
+        offset += written as u64;
+        while start < bufs.len() && written >= bufs[start].len() {
+            written -= bufs[start].len();
+            start += 1;
+        }
+        if written > 0 {
+            bufs[start] = &bufs[start][written..];
+        }
+    }
+
+    Ok(())
+}
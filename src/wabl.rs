@@ -5,56 +5,239 @@ use wal::*;
 use std::path::Path;
 use std::convert::AsRef;
 use page_store::PageStore;
+use lock::BusyHandler;
+use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use vfs::Vfs;
+use integrity::Corruption;
+use format::{DbHeader, Trunk, DB_HEADER_DATA_SIZE, TRUNK_HEADER_SIZE, DB_HEADER_PAGE_COUNT_OFFSET,
+             DB_HEADER_FREELIST_TRUNK_OFFSET, DB_HEADER_FREELIST_COUNT_OFFSET};
+use std::collections::BTreeSet;
 
 const DEFAULT_PAGE_SIZE: u32 = 4096;
+const DEFAULT_AUTO_CHECKPOINT: AutoCheckpoint = AutoCheckpoint::Frames(1000);
 
+/// A database connection. It can be shared between threads, e.g. in
+/// an `Arc`, with each thread running its own transactions.
 pub struct Wabl {
     ps: PageStore,
     wal: Wal,
+    checkpointer: Mutex<Checkpointer>,
 }
 
 pub struct ReadWabl<'a> {
-    ps: &'a mut PageStore,
-    wal: ReadWal<'a>
+    ps: &'a PageStore,
+    wal: ReadWal<'a>,
+    checkpointer: &'a Mutex<Checkpointer>,
 }
 
 pub struct WriteWabl<'a> {
-    ps: &'a mut PageStore,
+    ps: &'a PageStore,
     wal: WriteWal<'a>,
+    checkpointer: &'a Mutex<Checkpointer>,
+}
+
+/// When to checkpoint automatically after a commit
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AutoCheckpoint {
+    Disabled,
+    /// Once the log holds at least this many frames
+    Frames(u32),
+    /// Once the log holds at least this many bytes
+    Bytes(u64),
+}
+
+/// Called after every commit with the number of frames in the log
+pub type WalHook = Box<FnMut(FrameNum) + Send>;
+
+struct Checkpointer {
+    auto: AutoCheckpoint,
+    hook: Option<WalHook>,
 }
 
 impl Wabl {
-    fn new<P: AsRef<Path>>(p: &P) -> Result<Wabl> {
+    /// Opens the database at `p`, accessing it through `vfs`, e.g.
+    /// `Arc::new(OsVfs)`
+    pub fn new<P: AsRef<Path>>(vfs: Arc<Vfs>, p: &P) -> Result<Wabl> {
         let page_size = PageSize::new(DEFAULT_PAGE_SIZE);
-        Ok(Wabl {
-            ps: PageStore::new(p, page_size)?,
-            wal: Wal::new(p, page_size)?,
-        })
+        let wal = Wal::new(vfs.clone(), p, page_size)?;
+        let ps = PageStore::new(&*vfs, p, page_size, wal.busy_handler())?;
+        let wabl = Wabl {
+            ps: ps,
+            wal: wal,
+            checkpointer: Mutex::new(Checkpointer {
+                auto: DEFAULT_AUTO_CHECKPOINT,
+                hook: None,
+            }),
+        };
+
+        // The latest header may be in the log, so it can only be
+        // checked once the log is recovered
+        {
+            let mut tx = wabl.begin_read()?;
+            let header = tx.header()?;
+            let db_size = tx.db_size()?;
+            if let Some(c) = check_page_count(&wabl.ps, &header, db_size) {
+                bail!(ErrorKind::Corrupt(c));
+            }
+        }
+
+        Ok(wabl)
     }
 
-    fn begin_read(&mut self) -> Result<ReadWabl> {
+    pub fn begin_read(&self) -> Result<ReadWabl> {
         Ok(ReadWabl {
-            ps: &mut self.ps,
+            ps: &self.ps,
             wal: self.wal.begin_read()?,
+            checkpointer: &self.checkpointer,
         })
     }
 
-    fn checkpoint(&mut self) -> Result<()> {
-        let mut wal = self.wal.begin_checkpoint()?;
+    /// Starts a write transaction at the latest commit. The snapshot
+    /// is taken holding the write lock, so unlike upgrading a read
+    /// transaction this never fails with `BusySnapshot`.
+    pub fn begin_write(&self) -> Result<WriteWabl<'_>> {
+        WriteWabl::new(&self.ps, self.wal.begin_write()?, &self.checkpointer)
+    }
+
+    pub fn checkpoint(&self, mode: CheckpointMode) -> Result<CheckpointResult> {
+        checkpoint(&self.ps, &self.wal, mode)
+    }
+
+    /// Scans the database file, the log and the Wal index for damage,
+    /// listing every problem found rather than stopping at the first.
+    /// An empty list means the database is sound. Writers and
+    /// checkpoints wait while it runs.
+    pub fn check_integrity(&self) -> Result<Vec<Corruption>> {
+        let mut problems = self.wal.check_integrity()?;
+        problems.extend(self.ps.check_integrity(self.wal.busy_handler())?);
 
-        for page_num in wal.pages() {
-            let page = wal.read_page(*page_num)?
-                           .expect("checkpoint missing page");
-            self.ps.write_page(*page_num, page)?;
+        // A damaged header was reported above, wherever it is
+        let mut tx = self.begin_read()?;
+        if let Ok(header) = tx.header() {
+            let db_size = tx.db_size()?;
+            problems.extend(check_page_count(&self.ps, &header, db_size));
+            problems.extend(check_freelist(&self.ps, &mut tx, &header)?);
         }
 
-        self.ps.sync()?;
-        wal.next_epoch()
+        Ok(problems)
+    }
+
+    /// Sets when commits run a passive checkpoint. Use
+    /// `AutoCheckpoint::Disabled` to run checkpoints on your own
+    /// schedule instead.
+    pub fn set_auto_checkpoint(&mut self, auto: AutoCheckpoint) {
+        self.checkpointer.get_mut().expect("checkpointer").auto = auto;
+    }
+
+    /// Sets how hard commits and checkpoints try to survive a crash.
+    /// Individual transactions can override it.
+    pub fn set_synchronous(&mut self, synchronous: Synchronous) {
+        self.wal.set_synchronous(synchronous);
+    }
+
+    /// Lets commits share syncs of the log. See
+    /// `Wal::set_group_commit`.
+    pub fn set_group_commit(&mut self, window: Option<Duration>) {
+        self.wal.set_group_commit(window);
+    }
+
+    /// Sets what to do while other connections hold the database
+    /// busy. See `Wal::set_busy_handler`.
+    pub fn set_busy_handler(&mut self, busy: BusyHandler) {
+        self.wal.set_busy_handler(busy);
+    }
+
+    /// Sets a hook to run after every commit, e.g. to decide when to
+    /// checkpoint. It runs before any automatic checkpoint.
+    pub fn set_wal_hook(&mut self, hook: Option<WalHook>) {
+        self.checkpointer.get_mut().expect("checkpointer").hook = hook;
+    }
+}
+
+fn checkpoint(ps: &PageStore, wal: &Wal,
+              mode: CheckpointMode) -> Result<CheckpointResult> {
+    backfill(ps, wal.synchronous(), wal.begin_checkpoint(mode)?)
+}
+
+/// Copies the pages of a checkpoint to the page store
+fn backfill(ps: &PageStore, synchronous: Synchronous,
+            wal: Checkpoint) -> Result<CheckpointResult> {
+
+    if let Some(n) = wal.db_size() {
+        ps.resize_at_least(n)?;
+    }
+
+    for page_num in wal.pages() {
+        // Every page listed has a frame to read
+        if let Some(page) = wal.read_page(*page_num)? {
+            ps.write_page(*page_num, page)?;
+        }
+    }
+
+    if let Some(n) = wal.db_size() {
+        ps.truncate(n)?;
+    }
+
+    if synchronous != Synchronous::Off {
+        ps.sync()?;
+    }
+    wal.finish()
+}
+
+impl Checkpointer {
+    fn after_commit(this: &Mutex<Checkpointer>, ps: &PageStore, wal: &Wal) {
+        // Don't hold the mutex while checkpointing, so other threads
+        // can commit in the meantime
+        let full = {
+            let mut this = this.lock().expect("checkpointer");
+            if let Some(ref mut hook) = this.hook {
+                hook(wal.log_frames());
+            }
+
+            match this.auto {
+                AutoCheckpoint::Disabled => false,
+                AutoCheckpoint::Frames(n) => wal.log_frames() >= n,
+                AutoCheckpoint::Bytes(n) => wal.log_bytes() >= n,
+            }
+        };
+
+        // The transaction is durable by now, so the checkpoint can't
+        // fail it. One that can't run now is left to a later commit.
+        if full {
+            if let Ok(Some(checkpoint)) = wal.try_begin_checkpoint() {
+                let _ = backfill(ps, wal.synchronous(), checkpoint);
+            }
+        }
     }
 }
 
 impl<'a> ReadWabl<'a> {
-    fn read_page(&mut self, i: PageNum) -> Result<Page> {
+    /// The size of the database in pages, including page 0
+    pub fn db_size(&self) -> Result<PageNum> {
+        match self.wal.db_size() {
+            Some(n) => Ok(n),
+            None => self.ps.num_pages(),
+        }
+    }
+
+    /// The database header, as of this transaction's snapshot
+    pub fn header(&mut self) -> Result<DbHeader> {
+        let page = match self.wal.read_page(0)? {
+            Some(p) => p,
+            None => self.ps.read_page(0)?,
+        };
+        decode_header(self.ps, &page)
+    }
+
+    /// Reads page `i`, which can't be page 0
+    pub fn read_page(&mut self, i: PageNum) -> Result<Page> {
+        check_not_header(i)?;
+        let db_size = self.db_size()?;
+        if i >= db_size {
+            bail!(ErrorKind::PageOutOfRange(i, db_size));
+        }
+
         if let Some(p) = self.wal.read_page(i)? {
             return Ok(p);
         }
@@ -62,16 +245,94 @@ impl<'a> ReadWabl<'a> {
         self.ps.read_page(i)
     }
 
-    fn begin_write(self) -> Result<WriteWabl<'a>> {
-        Ok(WriteWabl {
-            ps: self.ps,
-            wal: self.wal.begin_write()?,
-        })
+    /// Upgrades to a write transaction. Fails with
+    /// `ErrorKind::BusySnapshot` if another transaction committed
+    /// since this one started; see `Wabl::begin_write`.
+    pub fn begin_write(self) -> Result<WriteWabl<'a>> {
+        WriteWabl::new(self.ps, self.wal.begin_write()?, self.checkpointer)
     }
 }
 
 impl<'a> WriteWabl<'a> {
-    fn read_page(&mut self, i: PageNum) -> Result<Page> {
+    fn new(ps: &'a PageStore, mut wal: WriteWal<'a>,
+           checkpointer: &'a Mutex<Checkpointer>) -> Result<WriteWabl<'a>> {
+        if wal.db_size().is_none() {
+            wal.set_db_size(ps.num_pages()?);
+        }
+
+        Ok(WriteWabl {
+            ps: ps,
+            wal: wal,
+            checkpointer: checkpointer,
+        })
+    }
+
+    /// The size of the database in pages, including page 0
+    pub fn db_size(&self) -> PageNum {
+        self.wal.db_size().expect("db size")
+    }
+
+    /// Sets the size of the database in pages, dropping any pages
+    /// past the end. Writing a page past the end grows it again.
+    /// Page 0 can't be dropped, and neither can pages on the
+    /// freelist, which fail with `FreePage`.
+    pub fn set_db_size(&mut self, n: PageNum) -> Result<()> {
+        if n == 0 {
+            bail!(ErrorKind::ReservedPage(0));
+        }
+        if n < self.db_size() {
+            if let Some(free) = self.free_page_from(n)? {
+                bail!(ErrorKind::FreePage(free));
+            }
+        }
+
+        // The new size is only recorded if the transaction writes
+        // something. If the database grows, the new last page is
+        // zeroed; otherwise the header, which changes on commit
+        // anyway, will do.
+        if n > self.db_size() {
+            self.wal.write_page(n - 1, Page::new(self.ps.page_size()))?;
+        } else {
+            let page = self.read_page_0()?;
+            self.wal.write_page(0, page)?;
+        }
+        self.wal.set_db_size(n);
+        Ok(())
+    }
+
+    /// The database header, including any changes made by this
+    /// transaction
+    pub fn header(&mut self) -> Result<DbHeader> {
+        let page = self.read_page_0()?;
+        decode_header(self.ps, &page)
+    }
+
+    /// Sets the root page of the schema, for the layers above
+    pub fn set_schema_root(&mut self, n: PageNum) -> Result<()> {
+        let mut header = self.header()?;
+        header.schema_root = n;
+        self.write_header(&header)
+    }
+
+    pub fn set_application_id(&mut self, id: u32) -> Result<()> {
+        let mut header = self.header()?;
+        header.application_id = id;
+        self.write_header(&header)
+    }
+
+    pub fn set_user_version(&mut self, version: u32) -> Result<()> {
+        let mut header = self.header()?;
+        header.user_version = version;
+        self.write_header(&header)
+    }
+
+    /// Reads page `i`, which can't be page 0
+    pub fn read_page(&mut self, i: PageNum) -> Result<Page> {
+        check_not_header(i)?;
+        if i >= self.db_size() {
+            bail!(ErrorKind::PageOutOfRange(i, self.db_size()));
+        }
+
         if let Some(p) = self.wal.read_page(i)? {
             return Ok(p);
         }
@@ -79,15 +340,303 @@ impl<'a> WriteWabl<'a> {
         self.ps.read_page(i)
     }
 
-    fn write_page(&mut self, i: PageNum, b: Page) -> Result<()> {
+    /// Writes page `i`, which can't be page 0
+    pub fn write_page(&mut self, i: PageNum, b: Page) -> Result<()> {
+        check_not_header(i)?;
         self.wal.write_page(i, b)
     }
 
-    fn commit(self) -> Result<()> {
-        self.wal.commit()
+    /// Returns an unused page, zeroed, reusing a freed page if there
+    /// is one and growing the database otherwise
+    pub fn allocate_page(&mut self) -> Result<PageNum> {
+        let mut header = self.header()?;
+        let n = if header.freelist_trunk == 0 {
+            self.db_size()
+        } else {
+            let trunk_num = header.freelist_trunk;
+            let mut trunk = self.read_trunk(trunk_num)?;
+            let db_size = self.db_size();
+            let n = match trunk.leaves.pop() {
+                Some(leaf) => {
+                    if leaf == 0 || leaf >= db_size {
+                        let offset = TRUNK_HEADER_SIZE + 4 * trunk.leaves.len() as u32;
+                        bail!(ErrorKind::Corrupt(freelist_corruption(
+                            self.ps, trunk_num, offset,
+                            format!("a free page below {}", db_size),
+                            format!("page {}", leaf))));
+                    }
+                    self.write_trunk(trunk_num, &trunk)?;
+                    leaf
+                }
+                // Hand out the trunk itself once it lists nothing
+                None => {
+                    if trunk.next == trunk_num || trunk.next >= db_size {
+                        bail!(ErrorKind::Corrupt(freelist_corruption(
+                            self.ps, trunk_num, 0,
+                            format!("another freelist trunk below {}", db_size),
+                            format!("page {}", trunk.next))));
+                    }
+                    header.freelist_trunk = trunk.next;
+                    trunk_num
+                }
+            };
+            header.freelist_count = match header.freelist_count.checked_sub(1) {
+                Some(count) => count,
+                None => bail!(ErrorKind::Corrupt(freelist_corruption(
+                    self.ps, 0, DB_HEADER_FREELIST_COUNT_OFFSET,
+                    "a count of the free pages listed".to_string(),
+                    "0".to_string()))),
+            };
+            self.write_header(&header)?;
+            n
+        };
+
+        self.write_page(n, Page::new(self.ps.page_size()))?;
+        Ok(n)
+    }
+
+    /// Adds page `n` to the freelist, for `allocate_page` to reuse.
+    /// Its contents are lost. Freeing a page that is already free
+    /// corrupts the freelist.
+    pub fn free_page(&mut self, n: PageNum) -> Result<()> {
+        check_not_header(n)?;
+        if n >= self.db_size() {
+            bail!(ErrorKind::PageOutOfRange(n, self.db_size()));
+        }
+
+        let mut header = self.header()?;
+        let capacity = Trunk::capacity(self.ps.page_size());
+        let mut listed = false;
+        if header.freelist_trunk != 0 {
+            let mut trunk = self.read_trunk(header.freelist_trunk)?;
+            if trunk.leaves.len() < capacity {
+                trunk.leaves.push(n);
+                self.write_trunk(header.freelist_trunk, &trunk)?;
+                listed = true;
+            }
+        }
+
+        // Otherwise the page becomes the first trunk
+        if !listed {
+            let trunk = Trunk {
+                next: header.freelist_trunk,
+                leaves: Vec::new(),
+            };
+            self.write_trunk(n, &trunk)?;
+            header.freelist_trunk = n;
+        }
+
+        header.freelist_count += 1;
+        self.write_header(&header)
+    }
+
+    /// The number of pages on the freelist
+    pub fn free_count(&mut self) -> Result<u32> {
+        Ok(self.header()?.freelist_count)
+    }
+
+    /// The first page on the freelist numbered `n` or above, if any
+    fn free_page_from(&mut self, n: PageNum) -> Result<Option<PageNum>> {
+        let mut seen = BTreeSet::new();
+        let mut next = self.header()?.freelist_trunk;
+        // A loop is left for `check_integrity` to report
+        while next != 0 && seen.insert(next) {
+            if next >= n {
+                return Ok(Some(next));
+            }
+            let trunk = self.read_trunk(next)?;
+            if let Some(&leaf) = trunk.leaves.iter().find(|&&leaf| leaf >= n) {
+                return Ok(Some(leaf));
+            }
+            next = trunk.next;
+        }
+        Ok(None)
+    }
+
+    fn read_page_0(&mut self) -> Result<Page> {
+        if let Some(p) = self.wal.read_page(0)? {
+            return Ok(p);
+        }
+
+        self.ps.read_page(0)
+    }
+
+    fn write_header(&mut self, header: &DbHeader) -> Result<()> {
+        let mut page = self.read_page_0()?;
+        let bytes = header.to_checksummed_bytes();
+        page.buf_mut()[..bytes.len()].copy_from_slice(&bytes);
+        self.wal.write_page(0, page)
+    }
+
+    /// Reads the first freelist trunk, page `n`
+    fn read_trunk(&mut self, n: PageNum) -> Result<Trunk> {
+        let db_size = self.db_size();
+        if n >= db_size {
+            bail!(ErrorKind::Corrupt(freelist_corruption(
+                self.ps, 0, DB_HEADER_FREELIST_TRUNK_OFFSET,
+                format!("a freelist trunk below {}", db_size),
+                format!("page {}", n))));
+        }
+        let page = self.read_page(n)?;
+        let ps = self.ps;
+        Trunk::decode(page.buf()).map_err(|e| {
+            ErrorKind::Corrupt(e.at(ps.path(), ps.page_offset(n), None, Some(n))).into()
+        })
+    }
+
+    fn write_trunk(&mut self, n: PageNum, trunk: &Trunk) -> Result<()> {
+        let mut page = Page::new(self.ps.page_size());
+        let bytes = trunk.to_bytes();
+        page.buf_mut()[..bytes.len()].copy_from_slice(&bytes);
+        self.write_page(n, page)
     }
 
-    fn rollback(self) -> Result<()> {
+    /// Overrides the `Wabl`'s synchronous level for this transaction
+    pub fn set_synchronous(&mut self, synchronous: Synchronous) {
+        self.wal.set_synchronous(synchronous);
+    }
+
+    pub fn commit(mut self) -> Result<()> {
+        // Every transaction that changes anything bumps the change
+        // counter and records the size
+        if self.wal.is_dirty() {
+            let mut header = self.header()?;
+            header.page_count = self.db_size();
+            header.change_counter = header.change_counter.wrapping_add(1);
+            self.write_header(&header)?;
+        }
+
+        let WriteWabl { ps, wal, checkpointer } = self;
+        let wal = wal.commit_and_release()?;
+        Checkpointer::after_commit(checkpointer, ps, wal);
+        Ok(())
+    }
+
+    pub fn rollback(self) -> Result<()> {
         self.wal.rollback()
     }
 }
+
+/// Checks the page count in `header` against the size of the
+/// database, which is the length of the database file unless the log
+/// records it
+fn check_page_count(ps: &PageStore, header: &DbHeader, db_size: PageNum) -> Option<Corruption> {
+    if header.page_count == db_size {
+        return None;
+    }
+
+    Some(Corruption {
+        file: ps.path().to_path_buf(),
+        offset: DB_HEADER_PAGE_COUNT_OFFSET as u64,
+        frame: None,
+        page: Some(0),
+        expected: format!("a page count of {}", db_size),
+        found: format!("{}", header.page_count),
+    })
+}
+
+/// Reports damage at `offset` in page `n`, which is part of the
+/// freelist, or page 0 for its header
+fn freelist_corruption(ps: &PageStore, n: PageNum, offset: u32,
+                       expected: String, found: String) -> Corruption {
+    Corruption {
+        file: ps.path().to_path_buf(),
+        offset: ps.page_offset(n) + offset as u64,
+        frame: None,
+        page: Some(n),
+        expected: expected,
+        found: found,
+    }
+}
+
+/// Follows the freelist in the snapshot of `tx`, reporting pages out
+/// of range, pages listed twice, loops, undecodable trunks and a
+/// count that doesn't match
+fn check_freelist(ps: &PageStore, tx: &mut ReadWabl,
+                  header: &DbHeader) -> Result<Vec<Corruption>> {
+    let mut problems = Vec::new();
+    let db_size = tx.db_size()?;
+    let mut seen = BTreeSet::new();
+    // Where the pointer to the next trunk is
+    let mut from = (0, DB_HEADER_FREELIST_TRUNK_OFFSET);
+    let mut next = header.freelist_trunk;
+
+    while next != 0 {
+        if next >= db_size || !seen.insert(next) {
+            let expected = if next >= db_size {
+                format!("a freelist trunk below {}", db_size)
+            } else {
+                "a freelist trunk not already listed".to_string()
+            };
+            problems.push(freelist_corruption(ps, from.0, from.1, expected,
+                                              format!("page {}", next)));
+            break;
+        }
+        let trunk = match Trunk::decode(tx.read_page(next)?.buf()) {
+            Ok(t) => t,
+            Err(e) => {
+                problems.push(e.at(ps.path(), ps.page_offset(next), None, Some(next)));
+                break;
+            }
+        };
+        for (i, &leaf) in trunk.leaves.iter().enumerate() {
+            let offset = TRUNK_HEADER_SIZE + 4 * i as u32;
+            if leaf == 0 || leaf >= db_size {
+                problems.push(freelist_corruption(ps, next, offset,
+                                                  format!("a free page below {}", db_size),
+                                                  format!("page {}", leaf)));
+            } else if !seen.insert(leaf) {
+                problems.push(freelist_corruption(ps, next, offset,
+                                                  "a free page not already listed".to_string(),
+                                                  format!("page {}", leaf)));
+            }
+        }
+        from = (next, 0);
+        next = trunk.next;
+    }
+
+    if problems.is_empty() && seen.len() as u64 != header.freelist_count as u64 {
+        problems.push(freelist_corruption(ps, 0, DB_HEADER_FREELIST_COUNT_OFFSET,
+                                          format!("a count of {} free pages", seen.len()),
+                                          format!("{}", header.freelist_count)));
+    }
+
+    Ok(problems)
+}
+
+/// Fails for page 0, which only `Wabl` itself may access
+fn check_not_header(i: PageNum) -> Result<()> {
+    if i == 0 {
+        bail!(ErrorKind::ReservedPage(i));
+    }
+    Ok(())
+}
+
+/// Decodes the header in page 0 and checks it against its checksum
+fn decode_header(ps: &PageStore, page: &Page) -> Result<DbHeader> {
+    let (header, stored) = DbHeader::decode(page.buf()).map_err(|e| {
+        Error::from(ErrorKind::Corrupt(e.at(ps.path(), 0, None, Some(0))))
+    })?;
+    if stored != header.checksum() {
+        bail!(ErrorKind::Corrupt(Corruption {
+            file: ps.path().to_path_buf(),
+            offset: DB_HEADER_DATA_SIZE as u64,
+            frame: None,
+            page: Some(0),
+            expected: format!("header checksum {}", header.checksum()),
+            found: format!("{}", stored),
+        }));
+    }
+    Ok(header)
+}
+
+/// Fails to compile unless `T` can be shared between threads
+#[allow(dead_code)]
+fn assert_send_sync<T: Send + Sync>() { }
+
+#[allow(dead_code)]
+fn assert_connections_send_sync() {
+    assert_send_sync::<Wabl>();
+    assert_send_sync::<Wal>();
+    assert_send_sync::<::wal_index::WalIndex>();
+}
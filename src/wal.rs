@@ -4,93 +4,209 @@ use page::Page;
 use std::mem;
 use errors::*;
 use wal_index::*;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::convert::AsRef;
 use std::marker::PhantomData;
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Write, Seek, SeekFrom, BufReader, BufWriter};
+use std::io::{self, Read, Write, Seek, SeekFrom, BufReader, BufWriter};
 use std::iter;
 use fs2::FileExt;
 use units::PageSize;
 use byteorder::*;
 use std::collections::BTreeMap;
-use std::rc::Rc;
-use std::cell::RefCell;
+use std::sync::Mutex;
+use std::sync::Arc;
+use vfs::{Vfs, VfsFile};
 use lock::*;
-use std::io::Error as IoError;
-use std::io::ErrorKind as IoErrorKind;
+use checksum::Checksum;
+use format::*;
+use integrity::Corruption;
+use rand;
+use std::thread;
+use std::time::Duration;
 
 pub type PageNum = u32;
 pub type FrameNum = u32;
 
+/// A connection to the log. It can be shared between threads, each
+/// running its own transactions.
 pub struct Wal {
-    file: Rc<RefCell<File>>,
+    vfs: Arc<Vfs>,
+    file: Box<VfsFile>,
+    path: PathBuf,
+    /// The database file, synced by group commit when a checkpoint
+    /// took a transaction's frames before they were synced
+    db_path: PathBuf,
     index: WalIndex,
     page_size: PageSize,
-    epoch: u64,
-    frame_map: FrameMap,
+    /// The shared index header as of the last transaction to start or
+    /// commit through this connection
+    latest: Mutex<IndexHeader>,
+    /// The directory holding the log, synced in `Synchronous::Extra`
+    dir: PathBuf,
+    synchronous: Synchronous,
+    /// If set, commits share syncs, waiting this long for others
+    /// to join in
+    group_commit: Option<Duration>,
+    /// Consulted while waiting for other connections
+    busy: BusyHandler,
 }
 
+/// The frames written by a transaction that hasn't committed yet
 struct FrameMap {
     num_frames: u32,
-    pages: BTreeMap<PageNum, FrameNum>,
+    /// The page in each frame, in frame order
+    frame_pages: Vec<PageNum>,
+    /// The cumulative checksum through the last frame
+    checksum: Checksum,
+    epoch: u64,
+    salt: Salt,
 }
 
 pub struct ReadWal<'a> {
-    wal: &'a mut Wal,
+    wal: &'a Wal,
     lock: ReadLock,
+    /// The shared index header when the transaction started
+    snapshot: IndexHeader,
 }
 
+/// A write transaction. Dropping it without committing rolls it back.
 pub struct WriteWal<'a> {
-    wal: &'a mut Wal,
+    wal: &'a Wal,
     lock: WriteLock,
-    uncommitted_frame_map: FrameMap,
-    disarm: bool,
+    snapshot: IndexHeader,
+    synchronous: Synchronous,
+    /// The size of the database in pages, if known
+    db_size: Option<PageNum>,
+    /// The pages written so far. They are only logged on commit, all
+    /// at once, so rewriting a page costs nothing.
+    dirty: BTreeMap<PageNum, Page>,
 }
 
 pub struct Checkpoint<'a> {
-    wal: &'a mut Wal,
+    wal: &'a Wal,
+    mode: CheckpointMode,
+    snapshot: IndexHeader,
     clock: CheckpointLock,
-    wlock: WriteLock,
+    /// Held in every mode but passive, to keep writers out
+    wlock: Option<WriteLock>,
+    /// Frames before this can be backfilled without disturbing
+    /// any reader
+    backfill_to: FrameNum,
+    /// The pages to backfill and the frames holding them
+    pages: BTreeMap<PageNum, FrameNum>,
+    /// The size of the database in pages after backfilling
+    db_size: Option<PageNum>,
+}
+
+/// How a checkpoint deals with concurrent readers and writers
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CheckpointMode {
+    /// Backfill as many frames as possible without waiting for
+    /// readers or writers
+    Passive,
+    /// Block writers and wait for readers of old snapshots to finish,
+    /// then backfill every frame
+    Full,
+    /// Like `Full`, then wait for all readers of the log to finish
+    /// and start a new epoch, so the next writer starts at frame 0
+    Restart,
+    /// Like `Restart`, and also truncate the log file to zero bytes
+    Truncate,
+}
+
+/// When to flush the log to disk
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Synchronous {
+    /// Never sync. A crash of the OS or a power failure can lose
+    /// committed transactions or corrupt the database.
+    Off,
+    /// Sync the log before each checkpoint, and the database after.
+    /// A power failure can lose recent commits, but the database
+    /// stays consistent.
+    Normal,
+    /// Also sync the log on every commit, so committed transactions
+    /// are durable
+    Full,
+    /// Also sync the directory holding the log on every commit, so
+    /// the log file itself survives a power failure
+    Extra,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct CheckpointResult {
+    /// The number of frames in the log when the checkpoint ran
+    pub log_frames: FrameNum,
+    /// How many of those are now in the database
+    pub backfilled_frames: FrameNum,
 }
 
 const MAGIC: u64 = 0x11a8b23d4760cdb4;
+/// The space at the start of the log for the header
 const HEADER_SIZE: u32 = 100;
-const FRAME_HEADER_SIZE: u32 = 16;
+/// How many times to retry reading the index header while a writer
+/// is updating it
+const INDEX_HEADER_RETRIES: u32 = 100;
+const DEFAULT_SYNCHRONOUS: Synchronous = Synchronous::Full;
+
+impl FrameMap {
+    /// An empty map for frames appended after the committed frames
+    /// of `snapshot`
+    fn following(snapshot: &IndexHeader) -> FrameMap {
+        FrameMap {
+            num_frames: snapshot.mx_frame,
+            frame_pages: Vec::new(),
+            checksum: Checksum(snapshot.frame_checksum[0], snapshot.frame_checksum[1]),
+            epoch: snapshot.epoch,
+            salt: Salt::of(snapshot),
+        }
+    }
+}
 
-#[derive(Debug, Eq, PartialEq)]
-struct Header {
-    magic: u64,
-    page_size: PageSize,
-    epoch: u64,
+/// The size of the database in pages as of `snapshot`, or `None` if
+/// the log doesn't know it, in which case it is the size of the
+/// database file
+fn db_size(snapshot: &IndexHeader) -> Option<PageNum> {
+    if snapshot.mx_frame > 0 {
+        Some(snapshot.db_size)
+    } else {
+        None
+    }
 }
 
 
-struct ReadLock(ShLock);
-struct WriteLock(ExLock);
-struct CheckpointLock(ExLock);
+struct ReadLock(ReadMark);
+struct WriteLock(ByteLock);
+struct CheckpointLock(ByteLock);
 trait ReadOrWriteLock { }
 impl ReadOrWriteLock for ReadLock { }
 impl ReadOrWriteLock for WriteLock { }
+impl ReadOrWriteLock for CheckpointLock { }
 
 impl Wal {
-    pub fn new<P: AsRef<Path>>(p: P, page_size: PageSize) -> Result<Wal> {
+    pub fn new<P: AsRef<Path>>(vfs: Arc<Vfs>, p: P, page_size: PageSize) -> Result<Wal> {
 
-        let index = WalIndex::new(p.as_ref())?;
+        let index = WalIndex::new(&*vfs, p.as_ref())?;
 
-        let file = OpenOptions::new()
-            .read(true).write(true).create(true)
-            .open(p.as_ref().with_extension("wal"))?;
+        let path = p.as_ref().with_extension("wal");
+        let file = vfs.open(&path)?;
+        let dir = match path.parent() {
+            Some(d) if d != Path::new("") => d.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
 
         let mut wal = Wal {
-            file: Rc::new(RefCell::new(file)),
+            vfs: vfs,
+            file: file,
+            path: path,
+            db_path: p.as_ref().with_extension("db"),
             index: index,
             page_size: page_size,
-            epoch: 0,
-            frame_map: FrameMap {
-                num_frames: 0,
-                pages: BTreeMap::new(),
-            },
+            latest: Mutex::new(IndexHeader::default()),
+            dir: dir,
+            synchronous: DEFAULT_SYNCHRONOUS,
+            group_commit: None,
+            busy: BusyHandler::Block,
         };
 
         wal.init(page_size)?;
@@ -101,83 +217,83 @@ impl Wal {
     fn init(&mut self, page_size: PageSize) -> Result<()> {
         let wlock = self.write_lock()?;
         let clock = self.checkpoint_lock()?;
-        let header = self.read_header(&wlock)?;
-        let mut write = true;
-        let header = if let Some(mut h) = header {
-            let header_ok = h.magic == MAGIC || h.page_size == page_size;
-            if !header_ok {
-                Header {
-                    magic: MAGIC,
-                    page_size: page_size,
-                    epoch: 0,
-                }
-            } else {
-                write = false;
-                h
-            }
-        } else {
-            Header {
-                magic: MAGIC,
-                page_size: page_size,
-                epoch: 0,
-            }
-        };
-
-        self.epoch = header.epoch;
-
-        if write {
-            self.write_header(header, &clock)?;
-        }
-
+        self.recover(&wlock, &clock)?;
         Ok(())
     }
 
-    pub fn begin_read(&mut self) -> Result<ReadWal> {
-        Ok(ReadWal::new(self)?)
+    pub fn synchronous(&self) -> Synchronous {
+        self.synchronous
     }
 
-    pub fn begin_write(&mut self) -> Result<WriteWal> {
-        Ok(WriteWal::new(self)?)
+    /// Sets the default for transactions started after this
+    pub fn set_synchronous(&mut self, synchronous: Synchronous) {
+        self.synchronous = synchronous;
     }
 
-    pub fn begin_checkpoint(&mut self) -> Result<Checkpoint> {
-        Ok(Checkpoint::new(self)?)
+    /// Enables or disables group commit.
+    ///
+    /// Normally a `Full` or `Extra` commit syncs the log before it
+    /// publishes its frames, holding the write lock the whole time.
+    /// With group commit the frames are published first and the write
+    /// lock released, then the committer sleeps for `window` before
+    /// syncing. Transactions that commit in the meantime, from any
+    /// thread or process, are covered by the same sync. Either way
+    /// `commit` doesn't return until the transaction is durable, but
+    /// with group commit other transactions may see it slightly
+    /// before then.
+    pub fn set_group_commit(&mut self, window: Option<Duration>) {
+        self.group_commit = window;
     }
 
-    fn read_header(&mut self, lock: &ReadOrWriteLock) -> Result<Option<Header>> {
-        let mut file = self.file.borrow_mut();
+    /// Sets what to do while waiting for locks held by other
+    /// connections, or for readers to finish during a checkpoint.
+    /// The default is to wait as long as it takes.
+    pub fn set_busy_handler(&mut self, busy: BusyHandler) {
+        self.busy = busy;
+    }
 
-        if file.metadata()?.len() == 0 {
-            return Ok(None);
-        }
+    pub fn busy_handler(&self) -> &BusyHandler {
+        &self.busy
+    }
 
-        file.seek(SeekFrom::Start(0))?;
-        let mut rdr = BufReader::with_capacity(HEADER_SIZE as usize, &mut *file);
-        
-        let magic = rdr.read_u64::<LittleEndian>()?;
-        let page_size = rdr.read_u32::<LittleEndian>()?;
-        let epoch = rdr.read_u64::<LittleEndian>()?;
+    pub fn begin_read(&self) -> Result<ReadWal> {
+        Ok(ReadWal::new(self)?)
+    }
 
-        let header = Header {
-            magic: magic,
-            page_size: PageSize::new(page_size),
-            epoch: epoch,
-        };
+    pub fn begin_write(&self) -> Result<WriteWal> {
+        Ok(WriteWal::new(self, None)?)
+    }
 
-        Ok(Some(header))
+    pub fn begin_checkpoint(&self, mode: CheckpointMode) -> Result<Checkpoint> {
+        Ok(Checkpoint::new(self, mode)?)
     }
 
-    fn write_header(&mut self, h: Header, lock: &CheckpointLock) -> Result<()> {
-        let mut file = self.file.borrow_mut();
-        file.seek(SeekFrom::Start(0))?;
-        let mut wtr = BufWriter::with_capacity(HEADER_SIZE as usize, &mut *file);
-        wtr.write_u64::<LittleEndian>(h.magic)?;
-        wtr.write_u32::<LittleEndian>(h.page_size.to_u32())?;
-        wtr.write_u64::<LittleEndian>(h.epoch)?;
-        if wtr.into_inner().is_err() {
-            bail!("writing Wal header");
+    /// Starts a passive checkpoint, or returns `None` without waiting
+    /// if another checkpoint is in progress
+    pub(crate) fn try_begin_checkpoint(&self) -> Result<Option<Checkpoint<'_>>> {
+        match self.try_checkpoint_lock()? {
+            Some(clock) => Ok(Some(Checkpoint::start(self, CheckpointMode::Passive, None, clock)?)),
+            None => Ok(None),
         }
+    }
+
+    /// Returns `None` if the header is missing, invalid or fails its
+    /// checksum. Any of those just means there is no log to recover,
+    /// e.g. after a crash while starting a new epoch.
+    fn read_header(&self, lock: &ReadOrWriteLock) -> Result<Option<WalHeader>> {
+        Ok(self.decode_header(lock)?.ok())
+    }
+
+    /// Reads the header, saying what is wrong with it if it's invalid
+    fn decode_header(&self, lock: &ReadOrWriteLock) -> Result<Decoded<WalHeader>> {
+        let len = self.file.len()?.min(WAL_HEADER_SIZE as u64);
+        let mut buf = vec![0; len as usize];
+        self.file.read_at(&mut buf, 0)?;
+        Ok(WalHeader::decode(&buf))
+    }
 
+    fn write_header(&self, h: &WalHeader, lock: &WriteLock) -> Result<()> {
+        self.file.write_at(&h.to_checksummed_bytes(), 0)?;
         Ok(())
     }
 
@@ -189,217 +305,736 @@ impl Wal {
         HEADER_SIZE as u64 + fr as u64 * self.frame_size() as u64
     }
 
-    fn seek_frame(&self, fr: FrameNum) -> Result<()> {
-        let offset = self.frame_offset(fr);
-        self.file.borrow_mut().seek(SeekFrom::Start(offset))?;
+    fn write_lock(&self) -> Result<WriteLock> {
+        Ok(WriteLock(self.index.write_lock(&self.busy)?))
+    }
 
-        Ok(())
+    fn checkpoint_lock(&self) -> Result<CheckpointLock> {
+        Ok(CheckpointLock(self.index.checkpoint_lock(&self.busy)?))
     }
 
-    fn seek_frame_offset(&self, fr: FrameNum, bytes: u64) -> Result<()> {
-        let offset = self.frame_offset(fr) + bytes;
-        self.file.borrow_mut().seek(SeekFrom::Start(offset))?;
+    fn try_checkpoint_lock(&self) -> Result<Option<CheckpointLock>> {
+        Ok(self.index.try_checkpoint_lock()?.map(CheckpointLock))
+    }
 
-        Ok(())
+    fn latest(&self) -> IndexHeader {
+        *self.latest.lock().expect("latest")
     }
 
-    fn read_lock(&self) -> Result<ReadLock> {
-        Ok(ReadLock(ShLock::new(self.file.clone())?))
+    fn set_latest(&self, h: IndexHeader) {
+        *self.latest.lock().expect("latest") = h;
     }
 
-    fn write_lock(&self) -> Result<WriteLock> {
-        Ok(WriteLock(self.index.write_lock()?))
+    /// The number of committed frames in the log, as of the last
+    /// transaction
+    pub fn log_frames(&self) -> FrameNum {
+        self.latest().mx_frame
     }
 
-    fn checkpoint_lock(&self) -> Result<CheckpointLock> {
-        Ok(CheckpointLock(ExLock::new(self.file.clone())?))
+    /// The size of the committed log in bytes, as of the last
+    /// transaction
+    pub fn log_bytes(&self) -> u64 {
+        self.frame_offset(self.latest().mx_frame)
     }
 
-    fn read_page(&self, bn: PageNum, lock: &ReadOrWriteLock) -> Result<Option<Page>>
+    /// Reads the last version of a page in the frames before
+    /// `mx_frame`, which belong to the log of `snapshot`
+    fn read_page(&self, bn: PageNum, mx_frame: FrameNum, snapshot: &IndexHeader,
+                 lock: &ReadOrWriteLock) -> Result<Option<Page>>
     {
-        if let Some(frame_num) = self.frame_map.pages.get(&bn).cloned() {
-            let page = self.read_page_frame(frame_num, lock)?;
+        if let Some(frame_num) = self.index.find_frame(bn, mx_frame)? {
+            let page = self.read_page_frame(frame_num, bn, snapshot, lock)?;
             Ok(Some(page))
         } else {
             Ok(None)
         }
     }
 
-    fn read_page_frame(&self, fr: FrameNum, lock: &ReadOrWriteLock) -> Result<Page> {
-        let mut page = Page::new(self.page_size);
+    /// Reads page `bn` from frame `fr`, checking that the frame
+    /// header agrees with the index about what the frame holds
+    fn read_page_frame(&self, fr: FrameNum, bn: PageNum, snapshot: &IndexHeader,
+                       lock: &ReadOrWriteLock) -> Result<Page> {
+        let offset = self.frame_offset(fr);
+        let mut buf = vec![0; self.frame_size() as usize];
+        match self.file.read_at(&mut buf, offset) {
+            Ok(()) => { }
+            Err(Error(ErrorKind::Io(ref e), _)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                bail!(ErrorKind::Corrupt(Corruption {
+                    file: self.path.clone(),
+                    offset: offset,
+                    frame: Some(fr),
+                    page: Some(bn),
+                    expected: format!("a frame of {} bytes", buf.len()),
+                    found: "the end of the file".to_string(),
+                }));
+            }
+            Err(e) => return Err(e),
+        }
+
+        let (header, _) = self.decode_frame_header(fr, &buf)?;
+        let salt = Salt::of(snapshot);
+        if header.page_num != bn || header.epoch != snapshot.epoch || header.salt != salt {
+            bail!(ErrorKind::Corrupt(Corruption {
+                file: self.path.clone(),
+                offset: offset,
+                frame: Some(fr),
+                page: Some(bn),
+                expected: format!("page {} of epoch {} with salt {}", bn, snapshot.epoch, salt),
+                found: format!("page {} of epoch {} with salt {}",
+                               header.page_num, header.epoch, header.salt),
+            }));
+        }
 
-        let u8_sz = mem::size_of::<u32>() as u64;
-        let u64_sz = mem::size_of::<u64>() as u64;
-        let header_sz = u8_sz * 2 + u64_sz;
-        self.seek_frame_offset(fr, header_sz)?;
-        let mut file = self.file.borrow_mut();
-        file.read_exact(page.buf_mut())?;
+        let mut page = Page::new(self.page_size);
+        page.buf_mut().copy_from_slice(&buf[FRAME_HEADER_SIZE as usize..]);
         Ok(page)
     }
 
-    fn write_frame(&mut self, i: PageNum, p: Page,
-                   fr_map: &mut FrameMap, lock: &WriteLock) -> Result<()> {
-        let frame_num = fr_map.num_frames;
-        self.write_frame_(frame_num, i, p, lock)?;
-        fr_map.num_frames += 1;
-        fr_map.pages.insert(i, frame_num);
-        Ok(())
-    }
+    /// Appends a frame for each of `pages` in a single vectored
+    /// write. The last is the commit frame, for a database of
+    /// `commit` pages.
+    fn write_frames(&self, pages: &BTreeMap<PageNum, Page>, commit: PageNum,
+                    fr_map: &mut FrameMap, lock: &WriteLock) -> Result<()> {
+        let first = fr_map.num_frames;
+        let mut headers = Vec::with_capacity(pages.len() + 1);
+
+        // The first writer after a restart writes the header for the
+        // new epoch, since a truncating checkpoint leaves none. It is
+        // padded out to meet the first frame.
+        if first == 0 {
+            let header = WalHeader {
+                magic: MAGIC,
+                page_size: self.page_size,
+                epoch: fr_map.epoch,
+                salt: fr_map.salt,
+            };
+            let mut buf = header.to_checksummed_bytes();
+            buf.resize(HEADER_SIZE as usize, 0);
+            headers.push(buf);
+        }
 
-    fn write_frame_(&mut self, frame_num: FrameNum,
-                    page_num: PageNum, b: Page, lock: &WriteLock) -> Result<()>
-    {
-        assert!(b.buf().len() as u32 == self.page_size.to_u32());
-        self.seek_frame(frame_num)?;
-        let mut file = self.file.borrow_mut();
-        
-        let mut wtr = BufWriter::with_capacity(self.frame_size() as usize, &mut *file);
-        wtr.write_u32::<LittleEndian>(page_num)?;
-        wtr.write_u32::<LittleEndian>(0)?;
-        wtr.write_u64::<LittleEndian>(self.epoch)?;
-        wtr.write_all(b.buf())?;
-        if wtr.into_inner().is_err() {
-            bail!("writing Wal frame")
+        for (n, (&i, p)) in pages.iter().enumerate() {
+            let header = FrameHeader {
+                page_num: i,
+                commit: if n + 1 == pages.len() { commit } else { 0 },
+                epoch: fr_map.epoch,
+                salt: fr_map.salt,
+            };
+            let mut buf = header.to_bytes();
+            fr_map.checksum = fr_map.checksum.update(&buf).update(p.buf());
+            buf.write_u32::<LittleEndian>(fr_map.checksum.0)?;
+            buf.write_u32::<LittleEndian>(fr_map.checksum.1)?;
+            headers.push(buf);
+            fr_map.num_frames += 1;
+            fr_map.frame_pages.push(i);
         }
 
+        let mut headers = headers.iter();
+        let mut bufs = Vec::with_capacity(pages.len() * 2 + 1);
+        let offset = if first == 0 {
+            bufs.push(&headers.next().expect("header")[..]);
+            0
+        } else {
+            self.frame_offset(first)
+        };
+        for (header, p) in headers.zip(pages.values()) {
+            bufs.push(&header[..]);
+            bufs.push(p.buf());
+        }
+        self.file.write_vectored_at(&bufs, offset)?;
+
         Ok(())
     }
 
-    fn update_frame_map(&mut self, lock: &ReadOrWriteLock) -> Result<()> {
-        let file_len = self.file.borrow().metadata()?.len();
+    /// Reads the commit field of frame `fr` without verifying it
+    fn read_commit_field(&self, fr: FrameNum) -> Result<PageNum> {
+        let mut buf = [0; FRAME_HEADER_SIZE as usize];
+        self.file.read_at(&mut buf, self.frame_offset(fr))?;
+        Ok(self.decode_frame_header(fr, &buf)?.0.commit)
+    }
 
-        let header = self.read_header(lock)?.expect("bad header");
-        if header.epoch != self.epoch {
-            println!("new epoch: {}", header.epoch);
-            self.frame_map.pages.clear();
-            self.frame_map.num_frames = 0;
-            self.epoch = header.epoch;
+    /// Reads and verifies the header of frame `fr`, which should
+    /// follow the frames in `fr_map`. Returns `None` if the frame is
+    /// not a valid continuation of the log.
+    fn read_frame_header(&self, fr: FrameNum, fr_map: &FrameMap, file_len: u64)
+                         -> Result<Option<(FrameHeader, Checksum)>> {
+        // Is there actually space allocated for this frame?
+        let next_frame_offset = self.frame_offset(fr + 1);
+        if next_frame_offset > file_len {
+            return Ok(None);
         }
 
-        let mut uncommitted = BTreeMap::new();
-        
-        for frame in self.frame_map.num_frames.. {
-            self.seek_frame(frame)?;
+        let mut buf = vec![0; self.frame_size() as usize];
+        self.file.read_at(&mut buf, self.frame_offset(fr))?;
+        let (header, stored) = self.decode_frame_header(fr, &buf)?;
 
-            let r = || -> Result<(PageNum, u32)> {
-                let err = || Err(IoError::from(IoErrorKind::UnexpectedEof).into());
-                let mut file = self.file.borrow_mut();
-                let mut rdr = BufReader::new(&mut *file);
-                let page_num = rdr.read_u32::<LittleEndian>()?;
-                let commit_flag = rdr.read_u32::<LittleEndian>()?;
-                let epoch = rdr.read_u64::<LittleEndian>()?;
+        // Frames from a previous epoch, or from a previous log that
+        // reused this epoch number, are not part of this log.
+        if header.epoch != fr_map.epoch || header.salt != fr_map.salt {
+            return Ok(None);
+        }
 
-                if epoch != self.epoch {
-                    return err();
-                }
+        let checksum = fr_map.checksum
+            .update(&buf[..FRAME_HEADER_DATA_SIZE as usize])
+            .update(&buf[FRAME_HEADER_SIZE as usize..]);
+        if checksum != stored {
+            return Ok(None);
+        }
 
-                // Is there actually space allocated for this frame?
-                let next_frame_offset = self.frame_offset(frame + 1);
-                if next_frame_offset > file_len {
-                    return err();
-                }
-                Ok((page_num, commit_flag))
-            }();
-
-            match r {
-                Err(Error(ErrorKind::Io(ref e), ..))
-                    if e.kind() == IoErrorKind::UnexpectedEof =>
-                {
-                    // No more frames
-                    return Ok(());
-                }
-                Err(e) => return Err(e),
-                Ok((page_num, commit_flag)) => {
-                    uncommitted.insert(page_num, frame);
-                    // If this is a commit frame then update the frame
-                    // map.
-                    if commit_flag != 0 {
-                        let new_pages = mem::replace(&mut uncommitted, BTreeMap::new());
-                        self.frame_map.pages.extend(new_pages.into_iter());
-                        self.frame_map.num_frames = frame + 1;
-                    }
+        Ok(Some((header, checksum)))
+    }
+
+    /// Checks that frame `fr` ends with the cumulative checksum
+    /// `expected`
+    fn frame_checksum_matches(&self, fr: FrameNum, expected: Checksum,
+                              file_len: u64) -> Result<bool> {
+        if self.frame_offset(fr + 1) > file_len {
+            return Ok(false);
+        }
+
+        let mut buf = [0; FRAME_HEADER_SIZE as usize];
+        self.file.read_at(&mut buf, self.frame_offset(fr))?;
+        let (_, stored) = self.decode_frame_header(fr, &buf)?;
+        Ok(stored == expected)
+    }
+
+    fn decode_frame_header(&self, fr: FrameNum, buf: &[u8]) -> Result<(FrameHeader, Checksum)> {
+        FrameHeader::decode(buf).map_err(|e| {
+            ErrorKind::Corrupt(e.at(&self.path, self.frame_offset(fr), Some(fr), None)).into()
+        })
+    }
+
+    /// Takes a snapshot of the shared index header. Returns `None` if
+    /// the header stays torn, meaning a writer died while updating
+    /// it, in which case the index must be recovered.
+    fn snapshot(&self) -> Option<IndexHeader> {
+        for _ in 0..INDEX_HEADER_RETRIES {
+            if let Some(h) = self.index.read_header() {
+                self.set_latest(h);
+                return Some(h);
+            }
+            thread::yield_now();
+        }
+
+        None
+    }
+
+    /// Brings the shared index up to date with the log, returning
+    /// the new header.
+    ///
+    /// The index is rebuilt from scratch if it doesn't describe this
+    /// log, e.g. because the `.shm` file is new or the `.wal` file was
+    /// recreated. Then any committed transactions in the log beyond
+    /// the end of the index are added to it. This is the only place
+    /// the log is scanned.
+    ///
+    /// Nobody may read the index while it is rebuilt, so along with
+    /// the write and checkpoint locks that waits for every reader to
+    /// finish, failing with `Busy` if the busy handler gives up.
+    /// Adding transactions past the end doesn't disturb readers.
+    fn recover(&self, lock: &WriteLock, clock: &CheckpointLock) -> Result<IndexHeader> {
+        let _recover_lock = self.index.recover_lock(&self.busy)?;
+        let file_len = self.file.len()?;
+
+        // Start a new log if there is no usable one, e.g. after the
+        // log was truncated by a checkpoint.
+        let header = match self.read_header(lock)? {
+            Some(ref h) if h.magic == MAGIC && h.page_size == self.page_size => {
+                WalHeader {
+                    magic: h.magic,
+                    page_size: h.page_size,
+                    epoch: h.epoch,
+                    salt: h.salt,
                 }
             }
+            _ => {
+                let h = WalHeader {
+                    magic: MAGIC,
+                    page_size: self.page_size,
+                    epoch: 0,
+                    salt: Salt::random(),
+                };
+                self.write_header(&h, lock)?;
+                h
+            }
+        };
+
+        let current = self.index.read_header();
+        let valid = match current {
+            Some(ref h) => {
+                h.epoch == header.epoch
+                    && h.salt == [header.salt.0, header.salt.1]
+                    && h.page_size == header.page_size.to_u32()
+                    && (h.mx_frame == 0 ||
+                        self.frame_checksum_matches(h.mx_frame - 1,
+                                                    Checksum(h.frame_checksum[0],
+                                                             h.frame_checksum[1]),
+                                                    file_len)?)
+            }
+            None => false,
+        };
+
+        let _marks = if valid {
+            None
+        } else {
+            Some(self.index.lock_all_read_marks(&self.busy)?)
+        };
+
+        let mut snapshot = current.unwrap_or_default();
+        if !valid {
+            self.index.set_n_backfill(0, &lock.0);
+            self.index.reset_synced(&lock.0);
+            let checksum = header.checksum();
+            snapshot.epoch = header.epoch;
+            snapshot.salt = [header.salt.0, header.salt.1];
+            snapshot.page_size = header.page_size.to_u32();
+            snapshot.mx_frame = 0;
+            snapshot.frame_checksum = [checksum.0, checksum.1];
         }
 
-        Ok(())
+        let mut uncommitted = FrameMap::following(&snapshot);
+
+        // Scan forward until the first frame that fails verification.
+        // Anything after that is a torn or abandoned write.
+        for frame in snapshot.mx_frame.. {
+            let (header, next) = match self.read_frame_header(frame, &uncommitted, file_len)? {
+                Some(r) => r,
+                None => break,
+            };
+            uncommitted.checksum = next;
+            uncommitted.num_frames = frame + 1;
+            uncommitted.frame_pages.push(header.page_num);
+
+            // If this is a commit frame then add the transaction to
+            // the index.
+            if header.commit != 0 {
+                let first = frame + 1 - uncommitted.frame_pages.len() as u32;
+                self.index.append(first, &uncommitted.frame_pages, &lock.0)?;
+                snapshot.mx_frame = uncommitted.num_frames;
+                snapshot.db_size = header.commit;
+                snapshot.frame_checksum = [uncommitted.checksum.0, uncommitted.checksum.1];
+                uncommitted.frame_pages.clear();
+            }
+        }
+
+        if !valid || Some(snapshot) != current {
+            snapshot = self.index.write_header(snapshot, &lock.0);
+        }
+        self.set_latest(snapshot);
+
+        Ok(snapshot)
+    }
+
+    /// Starts a new epoch after `snapshot`, so the next writer starts
+    /// at the beginning of the log, and returns the new header. Every
+    /// frame must already be backfilled, and no reader may be using
+    /// the log.
+    fn next_epoch(&self, snapshot: &IndexHeader, truncate: bool,
+                  wlock: &WriteLock, clock: &CheckpointLock) -> Result<IndexHeader> {
+        let header = WalHeader {
+            magic: MAGIC,
+            page_size: self.page_size,
+            epoch: snapshot.epoch + 1,
+            salt: Salt::random(),
+        };
+        let checksum = header.checksum();
+        let mut h = *snapshot;
+        h.epoch = header.epoch;
+        h.salt = [header.salt.0, header.salt.1];
+        h.mx_frame = 0;
+        h.frame_checksum = [checksum.0, checksum.1];
+
+        if truncate {
+            // The next writer writes the new header
+            self.file.set_len(0)?;
+        } else {
+            self.write_header(&header, wlock)?;
+        }
+        let h = self.index.write_header(h, &wlock.0);
+        self.index.set_n_backfill(0, &clock.0);
+        self.set_latest(h);
+
+        Ok(h)
     }
 
-    fn commit(&mut self, frame_map: &FrameMap, lock: &WriteLock) -> Result<()> {
-        if frame_map.num_frames == 0 {
+    /// Publishes the frames in `frame_map`, which follow `snapshot`,
+    /// and returns the new header
+    fn commit(&self, snapshot: &IndexHeader, frame_map: &FrameMap, db_size: PageNum,
+              synchronous: Synchronous, lock: &WriteLock) -> Result<IndexHeader> {
+        if frame_map.frame_pages.is_empty() {
+            return Ok(*snapshot);
+        }
+
+        // Make the frames durable before anyone can see them, unless
+        // the sync is left to group commit
+        if self.group_commit.is_none() && self.sync_log(synchronous)? {
+            self.index.set_synced(snapshot.epoch, frame_map.num_frames);
+        }
+
+        // Publish the transaction to the index
+        let first = snapshot.mx_frame;
+        self.index.append(first, &frame_map.frame_pages, &lock.0)?;
+        let mut h = *snapshot;
+        h.mx_frame = frame_map.num_frames;
+        h.db_size = db_size;
+        h.frame_checksum = [frame_map.checksum.0, frame_map.checksum.1];
+        let h = self.index.write_header(h, &lock.0);
+        self.set_latest(h);
+
+        Ok(h)
+    }
+
+    /// Syncs the log as a commit at level `synchronous` requires.
+    /// Returns whether it synced.
+    fn sync_log(&self, synchronous: Synchronous) -> Result<bool> {
+        match synchronous {
+            Synchronous::Off | Synchronous::Normal => Ok(false),
+            Synchronous::Full => {
+                self.file.sync()?;
+                Ok(true)
+            }
+            Synchronous::Extra => {
+                self.file.sync()?;
+                self.vfs.sync_dir(&self.dir)?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Waits until the first `frames` frames of `epoch` are on disk,
+    /// syncing them if nobody else does. Called by group commit after
+    /// the write lock is released.
+    fn wait_synced(&self, epoch: u64, frames: FrameNum,
+                   synchronous: Synchronous) -> Result<()> {
+        let window = match self.group_commit {
+            Some(w) => w,
+            None => return Ok(()),
+        };
+        if synchronous == Synchronous::Off || synchronous == Synchronous::Normal {
             return Ok(());
         }
 
-        // Set the commit flag on last written page
-        let frame = frame_map.num_frames - 1;
-        let commit_flag_offset = mem::size_of::<u32>() as u64;
-        self.seek_frame_offset(frame, commit_flag_offset)?;
-        let mut file = self.file.borrow_mut();
-        file.write_u32::<LittleEndian>(1)?;
+        if frames == 0 || self.index.is_synced(epoch, frames) {
+            return Ok(());
+        }
+        thread::sleep(window);
+        if self.index.is_synced(epoch, frames) {
+            return Ok(());
+        }
 
-        // Update the frame map
-        self.frame_map.num_frames = frame_map.num_frames;
-        self.frame_map.pages.extend(frame_map.pages.iter());
+        // Sync everything committed so far, not just our frames, so
+        // that later committers can skip their sync
+        let target = match self.current_header() {
+            Some(h) if h.epoch != epoch => return self.sync_checkpointed(),
+            Some(h) => h.mx_frame.max(frames),
+            None => frames,
+        };
+        self.sync_log(synchronous)?;
+
+        // If the log moved on to a new epoch meanwhile, the sync may
+        // have missed our frames
+        match self.current_header() {
+            Some(h) if h.epoch != epoch => self.sync_checkpointed(),
+            _ => {
+                self.index.set_synced(epoch, target);
+                Ok(())
+            }
+        }
+    }
+
+    /// The shared index header, if it can be read
+    fn current_header(&self) -> Option<IndexHeader> {
+        for _ in 0..INDEX_HEADER_RETRIES {
+            if let Some(h) = self.index.read_header() {
+                return Some(h);
+            }
+            thread::yield_now();
+        }
+
+        None
+    }
 
+    /// Syncs the database file, for a group committer whose frames
+    /// were checkpointed into it and the log restarted before anyone
+    /// synced them. The checkpoint may not have synced them either,
+    /// e.g. if it ran with `Synchronous::Off`.
+    fn sync_checkpointed(&self) -> Result<()> {
+        self.vfs.open(&self.db_path)?.sync()?;
         Ok(())
     }
 
+    /// Checks the log and the index against each other, listing
+    /// every problem found. Blocks writers and checkpoints while it
+    /// runs.
+    pub fn check_integrity(&self) -> Result<Vec<Corruption>> {
+        let wlock = self.write_lock()?;
+        let clock = self.checkpoint_lock()?;
+        let mut problems = Vec::new();
+
+        // Nothing else can be checked without the index header
+        let snapshot = match self.index.read_header() {
+            Some(h) => h,
+            None => {
+                problems.push(self.index.header_corruption(&wlock.0));
+                return Ok(problems);
+            }
+        };
+        problems.extend(self.index.check_checkpoint_info(&snapshot));
+        if snapshot.mx_frame == 0 {
+            return Ok(problems);
+        }
+
+        let corruption = |offset: u64, frame: Option<FrameNum>, page: Option<PageNum>,
+                          expected: String, found: String| Corruption {
+            file: self.path.clone(),
+            offset: offset,
+            frame: frame,
+            page: page,
+            expected: expected,
+            found: found,
+        };
+
+        let expected_header = WalHeader {
+            magic: MAGIC,
+            page_size: self.page_size,
+            epoch: snapshot.epoch,
+            salt: Salt::of(&snapshot),
+        };
+        let describe = |h: &WalHeader| {
+            format!("a log of epoch {} with salt {} and {} byte pages",
+                    h.epoch, h.salt, h.page_size.to_u32())
+        };
+        match self.decode_header(&wlock)? {
+            Ok(ref h) if *h == expected_header => { }
+            Ok(ref h) => {
+                problems.push(corruption(0, None, None, describe(&expected_header), describe(h)));
+            }
+            Err(e) => problems.push(e.at(&self.path, 0, None, None)),
+        }
+
+        let file_len = self.file.len()?;
+        let salt = Salt::of(&snapshot);
+        let mut checksum = expected_header.checksum();
+        let mut buf = vec![0; self.frame_size() as usize];
+        for fr in 0..snapshot.mx_frame {
+            let offset = self.frame_offset(fr);
+            if self.frame_offset(fr + 1) > file_len {
+                problems.push(corruption(offset, Some(fr), None,
+                                         format!("{} committed frames", snapshot.mx_frame),
+                                         format!("the log ending in frame {}", fr)));
+                break;
+            }
+
+            self.file.read_at(&mut buf, offset)?;
+            let (header, stored) = self.decode_frame_header(fr, &buf)?;
+            let page = Some(header.page_num);
+
+            if header.epoch != snapshot.epoch || header.salt != salt {
+                problems.push(corruption(offset, Some(fr), page,
+                                         format!("epoch {} with salt {}", snapshot.epoch, salt),
+                                         format!("epoch {} with salt {}", header.epoch, header.salt)));
+            }
+
+            let computed = checksum
+                .update(&buf[..FRAME_HEADER_DATA_SIZE as usize])
+                .update(&buf[FRAME_HEADER_SIZE as usize..]);
+            let intact = computed == stored;
+            if !intact {
+                problems.push(corruption(offset, Some(fr), page,
+                                         format!("checksum {}", computed),
+                                         format!("{}", stored)));
+            }
+            // Check the following frames against what they were
+            // chained to, so one damaged frame is only reported once
+            checksum = stored;
+
+            if fr == snapshot.mx_frame - 1 {
+                if header.commit != snapshot.db_size {
+                    problems.push(corruption(offset, Some(fr), page,
+                                             format!("a commit of {} pages", snapshot.db_size),
+                                             format!("{} pages", header.commit)));
+                }
+                let committed = Checksum(snapshot.frame_checksum[0], snapshot.frame_checksum[1]);
+                if stored != committed {
+                    problems.push(corruption(offset, Some(fr), page,
+                                             format!("the committed checksum {}", committed),
+                                             format!("{}", stored)));
+                }
+            }
+
+            // A damaged frame's page number can't be checked against
+            // the index
+            if intact {
+                problems.extend(self.index.check_frame(fr, header.page_num)?);
+            }
+        }
+
+        Ok(problems)
+    }
+
     pub fn dump(&self) {
+        let latest = self.latest();
         println!("----");
-        println!("epoch: {}", self.epoch);
-        println!("frames: {}", self.frame_map.num_frames);
-        for (page, frame)  in &self.frame_map.pages {
+        println!("epoch: {}", latest.epoch);
+        println!("frames: {}", latest.mx_frame);
+        let pages = self.index.pages(latest.mx_frame).expect("index");
+        for (page, frame)  in &pages {
             println!("page {}: frame {}", page, frame);
         }
     }
 }
 
 impl<'a> ReadWal<'a> {
-    fn new(wal: &'a mut Wal) -> Result<ReadWal<'a>> {
-        let lock = wal.read_lock()?;
-        wal.update_frame_map(&lock)?;
-        Ok(ReadWal {
-            wal: wal,
-            lock: lock,
-        })
+    fn new(wal: &'a Wal) -> Result<ReadWal<'a>> {
+        let mut busy = wal.busy.start();
+        loop {
+            let snapshot = match wal.snapshot() {
+                Some(s) => s,
+                None => {
+                    // Wait for anyone already rebuilding the index
+                    // rather than queueing up to do it again
+                    if wal.index.is_recovering()? {
+                        busy.wait()?;
+                        continue;
+                    }
+                    let wlock = wal.write_lock()?;
+                    let clock = wal.checkpoint_lock()?;
+                    wal.recover(&wlock, &clock)?;
+                    continue;
+                }
+            };
+
+            // If the whole snapshot is already in the database then
+            // don't depend on the log at all, so it can restart.
+            let mark = if snapshot.mx_frame == wal.index.n_backfill() {
+                0
+            } else {
+                snapshot.mx_frame
+            };
+
+            let lock = match wal.index.begin_read(mark)? {
+                Some(l) => ReadLock(l),
+                None => {
+                    // All marks are in use by other snapshots
+                    busy.wait()?;
+                    continue;
+                }
+            };
+
+            // A checkpoint only respects marks it can see. If the log
+            // changed before ours became visible then try again with
+            // a fresh snapshot.
+            if wal.index.read_header() != Some(snapshot) {
+                continue;
+            }
+
+            return Ok(ReadWal {
+                wal: wal,
+                lock: lock,
+                snapshot: snapshot,
+            });
+        }
     }
 
     pub fn read_page(&self, i: PageNum) -> Result<Option<Page>> {
-        self.wal.read_page(i, &self.lock)
+        self.wal.read_page(i, self.lock.0.mark(), &self.snapshot, &self.lock)
+    }
+
+    /// The size of the database in pages in this snapshot, or `None`
+    /// if the log doesn't know it, in which case it is the size of
+    /// the database file
+    pub fn db_size(&self) -> Option<PageNum> {
+        db_size(&self.snapshot)
     }
 
+    /// Upgrades to a write transaction.
+    ///
+    /// Fails with `ErrorKind::BusySnapshot` if another transaction
+    /// changed the log since this one started, since writing would
+    /// build on data this transaction never saw. The caller should
+    /// start over with a new read transaction.
     pub fn begin_write(self) -> Result<WriteWal<'a>> {
-        // Drop the read lock before taking the write lock.
-        // update_frame_map will deal with any log changes between the
-        // two. FIXME: this is too tricky!
-        let wal = self.wal;
-        { let _ = self.lock; }
-        Ok(WriteWal::new(wal)?)
+        // The read lock has to go before taking the write lock, since
+        // a restarting checkpoint holds the write lock while it waits
+        // for readers. Holding the write lock, check that nothing
+        // happened in between.
+        let ReadWal { wal, lock, snapshot } = self;
+        drop(lock);
+        Ok(WriteWal::new(wal, Some(snapshot))?)
     }
 }
 
 impl<'a> WriteWal<'a> {
-    fn new(wal: &'a mut Wal) -> Result<WriteWal<'a>> {
+    /// Starts a write transaction. If `expected` is set, fails unless
+    /// the log is still in that state.
+    fn new(wal: &'a Wal, expected: Option<IndexHeader>) -> Result<WriteWal<'a>> {
         let lock = wal.write_lock()?;
-        wal.update_frame_map(&lock)?;
-        let num_frames = wal.frame_map.num_frames;
+        let mut snapshot = match wal.snapshot() {
+            Some(s) => s,
+            None => wal.recover(&lock, &wal.checkpoint_lock()?)?,
+        };
+
+        if let Some(expected) = expected {
+            if snapshot != expected {
+                bail!(ErrorKind::BusySnapshot);
+            }
+        }
+
+        // If every frame has been backfilled and nobody is reading the
+        // log, start over at the beginning of the log instead of
+        // growing it. Don't wait for a checkpoint in progress though.
+        let mx_frame = snapshot.mx_frame;
+        if mx_frame > 0 && wal.index.n_backfill() == mx_frame {
+            if let Some(clock) = wal.try_checkpoint_lock()? {
+                if let Some(_marks) = wal.index.lock_read_marks()? {
+                    snapshot = wal.next_epoch(&snapshot, false, &lock, &clock)?;
+                }
+            }
+        }
+
         Ok(WriteWal {
             wal: wal,
             lock: lock,
-            uncommitted_frame_map: FrameMap {
-                num_frames: num_frames,
-                pages: BTreeMap::new(),
-            },
-            disarm: false,
+            snapshot: snapshot,
+            synchronous: wal.synchronous,
+            db_size: db_size(&snapshot),
+            dirty: BTreeMap::new(),
         })
     }
 
+    /// The size of the database in pages, or `None` if the log
+    /// doesn't know it, in which case it is the size of the database
+    /// file
+    pub fn db_size(&self) -> Option<PageNum> {
+        self.db_size
+    }
+
+    /// Sets the size of the database in pages, dropping any pages
+    /// past the end. Writing a page past the end grows it again.
+    ///
+    /// If the log doesn't know the size, it must be set from the
+    /// database file before committing any pages, or the commit fails
+    /// with `UnknownDbSize`.
+    pub fn set_db_size(&mut self, n: PageNum) {
+        self.dirty.split_off(&n);
+        self.db_size = Some(n);
+    }
+
+    /// Overrides the `Wal`'s synchronous level for this transaction
+    pub fn set_synchronous(&mut self, synchronous: Synchronous) {
+        self.synchronous = synchronous;
+    }
+
+    /// Whether the transaction has written any pages
+    pub fn is_dirty(&self) -> bool {
+        !self.dirty.is_empty()
+    }
+
     pub fn read_page(&self, i: PageNum) -> Result<Option<Page>> {
-        if let Some(frame_num) = self.uncommitted_frame_map.pages.get(&i).cloned() {
-            let page = self.wal.read_page_frame(frame_num, &self.lock)?;
-            Ok(Some(page))
+        if let Some(p) = self.dirty.get(&i) {
+            Ok(Some(p.clone()))
         } else {
-            self.wal.read_page(i, &self.lock)
+            self.wal.read_page(i, self.snapshot.mx_frame, &self.snapshot, &self.lock)
         }
     }
 
@@ -409,25 +1044,65 @@ impl<'a> WriteWal<'a> {
     /// page store. Wabl will know to extend the page store during
     /// checkpointing.
     pub fn write_page(&mut self, i: PageNum, p: Page) -> Result<()> {
-        self.wal.write_frame(i, p, &mut self.uncommitted_frame_map, &self.lock)
-    }
+        let page_size = self.wal.page_size.to_u32();
+        if p.buf().len() as u32 != page_size {
+            bail!(ErrorKind::PageSizeMismatch(page_size, p.buf().len() as u32));
+        }
+        let end = match i.checked_add(1) {
+            Some(end) => end,
+            None => bail!(ErrorKind::Full),
+        };
 
-    pub fn commit(mut self) -> Result<()> {
-        self.disarm = true;
-        self.wal.commit(&self.uncommitted_frame_map, &self.lock)
+        self.dirty.insert(i, p);
+        if let Some(n) = self.db_size {
+            self.db_size = Some(n.max(end));
+        }
+        Ok(())
     }
 
-    pub fn rollback(mut self) -> Result<()> {
-        self.disarm = true;
-        panic!()
+    pub fn commit(self) -> Result<()> {
+        self.commit_and_release().map(|_| ())
     }
-}
 
-impl<'a> Drop for WriteWal<'a> {
-    fn drop(&mut self) {
-        if !self.disarm {
-            panic!("WriteWal drop bomb")
+    /// Commits, releases the write lock, and hands back the `Wal` so
+    /// that the caller can go on to checkpoint it.
+    pub(crate) fn commit_and_release(self) -> Result<&'a Wal> {
+        let db_size = match self.db_size {
+            Some(n) => n,
+            None if self.dirty.is_empty() => 0,
+            // Guessing from the pages written would shrink the database
+            None => bail!(ErrorKind::UnknownDbSize),
+        };
+        // A size of 0 can't be recorded, since it marks frames that
+        // don't commit
+        if self.db_size == Some(0) {
+            bail!(ErrorKind::PageOutOfRange(0, 0));
         }
+        let mut frame_map = FrameMap::following(&self.snapshot);
+        if !self.dirty.is_empty() {
+            self.wal.write_frames(&self.dirty, db_size, &mut frame_map, &self.lock)?;
+        }
+        self.wal.commit(&self.snapshot, &frame_map, db_size,
+                        self.synchronous, &self.lock)?;
+        let epoch = self.snapshot.epoch;
+        let frames = if frame_map.frame_pages.is_empty() {
+            0
+        } else {
+            frame_map.num_frames
+        };
+        let synchronous = self.synchronous;
+        let WriteWal { wal, lock, .. } = self;
+
+        // Let the next writer in while we wait for the sync
+        drop(lock);
+        wal.wait_synced(epoch, frames, synchronous)?;
+        Ok(wal)
+    }
+
+    /// Discards the pages written since `begin_write`. Nothing has
+    /// been logged yet, so there is nothing to undo.
+    pub fn rollback(self) -> Result<()> {
+        Ok(())
     }
 }
 
@@ -435,38 +1110,141 @@ use std::collections::btree_map;
 type Pages<'a> = btree_map::Keys<'a, PageNum, FrameNum>;
 
 impl<'a> Checkpoint<'a> {
-    fn new(wal: &'a mut Wal) -> Result<Checkpoint<'a>> {
+    fn new(wal: &'a Wal, mode: CheckpointMode) -> Result<Checkpoint<'a>> {
         // NB field order / unlocking order
-        let wlock = wal.write_lock()?;
+        let wlock = if mode == CheckpointMode::Passive {
+            None
+        } else {
+            Some(wal.write_lock()?)
+        };
         let clock = wal.checkpoint_lock()?;
-        wal.update_frame_map(&wlock)?;
-        wal.file.borrow().sync_data()?;
-        Ok(Checkpoint {
-            wal: wal,
-            clock: clock,
-            wlock: wlock,
-        })
+        Checkpoint::start(wal, mode, wlock, clock)
+    }
+
+    fn start(wal: &'a Wal, mode: CheckpointMode, wlock: Option<WriteLock>,
+             mut clock: CheckpointLock) -> Result<Checkpoint<'a>> {
+        let mut busy = wal.busy.start();
+
+        loop {
+            let snapshot = match wal.snapshot() {
+                Some(s) => s,
+                None => {
+                    match wlock {
+                        Some(ref wlock) => { wal.recover(wlock, &clock)?; }
+                        None => {
+                            // The write lock is always taken before the
+                            // checkpoint lock.
+                            drop(clock);
+                            let wlock = wal.write_lock()?;
+                            clock = wal.checkpoint_lock()?;
+                            wal.recover(&wlock, &clock)?;
+                        }
+                    }
+                    continue;
+                }
+            };
+
+            // Don't overwrite database pages that a reader of an
+            // older snapshot may still need.
+            let mx_frame = snapshot.mx_frame;
+            let backfill_to = match wal.index.oldest_read_mark()? {
+                Some(mark) => mark.min(mx_frame),
+                None => mx_frame,
+            };
+            let n_backfill = wal.index.n_backfill();
+
+            let complete = backfill_to == mx_frame || n_backfill == mx_frame;
+            if mode != CheckpointMode::Passive && !complete {
+                busy.wait()?;
+                continue;
+            }
+
+            // The commit frame ending the backfilled frames records
+            // the size of the database
+            let end = backfill_to.max(n_backfill);
+            let db_size = if end == mx_frame {
+                db_size(&snapshot)
+            } else if end > 0 {
+                Some(wal.read_commit_field(end - 1)?)
+            } else {
+                None
+            };
+
+            let pages = wal.index.pages(backfill_to)?.into_iter()
+                .filter(|&(_, frame)| frame >= n_backfill)
+                .filter(|&(page, _)| db_size.is_none_or(|n| page < n))
+                .collect::<BTreeMap<_, _>>();
+
+            if end > n_backfill && wal.synchronous != Synchronous::Off {
+                wal.file.sync()?;
+                wal.index.set_synced(snapshot.epoch, mx_frame);
+            }
+
+            return Ok(Checkpoint {
+                wal: wal,
+                mode: mode,
+                snapshot: snapshot,
+                clock: clock,
+                wlock: wlock,
+                backfill_to: end,
+                pages: pages,
+                db_size: db_size,
+            });
+        }
     }
 
     pub fn pages(&self) -> Pages {
-        self.wal.frame_map.pages.keys()
+        self.pages.keys()
+    }
+
+    /// The size of the database in pages once `pages` have been
+    /// copied to it, or `None` if the log doesn't know it. Pages past
+    /// the end can be dropped from the database file.
+    pub fn db_size(&self) -> Option<PageNum> {
+        self.db_size
     }
 
     pub fn read_page(&self, i: PageNum) -> Result<Option<Page>> {
-        self.wal.read_page(i, &self.wlock)
+        if let Some(frame_num) = self.pages.get(&i).cloned() {
+            let page = self.wal.read_page_frame(frame_num, i, &self.snapshot, &self.clock)?;
+            Ok(Some(page))
+        } else {
+            Ok(None)
+        }
     }
 
-    pub fn next_epoch(self) -> Result<()> {
-        // Update the epoch in the header. Don't update our own
-        // header state. That will happen like any other reader next
-        // time we do a read.
-        let header = Header {
-            magic: MAGIC,
-            page_size: self.wal.page_size,
-            epoch: self.wal.epoch + 1,
+    /// Records that `pages` have been copied to the database. In
+    /// restart and truncate modes, then waits for all readers to
+    /// leave the log and starts a new epoch.
+    pub fn finish(self) -> Result<CheckpointResult> {
+        self.wal.index.set_n_backfill(self.backfill_to, &self.clock.0);
+
+        let result = CheckpointResult {
+            log_frames: self.snapshot.mx_frame,
+            backfilled_frames: self.backfill_to,
         };
-        self.wal.write_header(header, &self.clock)?;
-        
-        Ok(())
+
+        let restart = match self.mode {
+            CheckpointMode::Passive | CheckpointMode::Full => false,
+            CheckpointMode::Restart | CheckpointMode::Truncate => true,
+        };
+        if !restart {
+            return Ok(result);
+        }
+
+        let mut busy = self.wal.busy.start();
+        let _marks = loop {
+            match self.wal.index.lock_read_marks()? {
+                Some(marks) => break marks,
+                None => busy.wait()?,
+            }
+        };
+        let wlock = self.wlock.as_ref().expect("restarting without the write lock");
+        let truncate = self.mode == CheckpointMode::Truncate;
+        self.wal.next_epoch(&self.snapshot, truncate, wlock, &self.clock)?;
+
+        Ok(result)
     }
+
 }
+
@@ -1,62 +1,603 @@
+//! The shared-memory Wal index
+//!
+//! The index lives in the `.shm` file and is mapped into every
+//! process using the database. It holds the state of the log (epoch,
+//! salt and number of committed frames) and a hash table from page
+//! number to frame, so that readers can find pages without scanning
+//! the log.
+//!
+//! The file is divided into regions of `REGION_SIZE` bytes. Region 0
+//! holds the header. Each later region holds one segment of the hash
+//! table, covering `SEGMENT_FRAMES` frames: the page number stored in
+//! each frame, followed by the hash slots.
+//!
+//! Only the holder of the write lock modifies the index. Readers
+//! don't lock it. The header is stored twice so that readers can
+//! detect a torn read, and readers only consult hash entries for
+//! frames below the `mx_frame` of the header they read.
+//!
+//! After the header come the checkpoint info and the read marks. Each
+//! active reader holds a read mark recording the last frame of its
+//! snapshot, and the checkpointer never backfills past the oldest
+//! one. A mark of 0 means the reader's whole snapshot was already in
+//! the database, so it doesn't use the log at all.
+//!
+//! Connections coordinate with locks on single bytes of region 0,
+//! past the data (fcntl locks in the `.shm` file with `OsVfs`): one
+//! each for writing, checkpointing and recovery, and one per read
+//! mark. Readers hold their mark's byte
+//! shared, and a mark is only changed while holding it exclusively,
+//! so a mark is in use exactly when its byte can't be locked
+//! exclusively.
+
 use lock::*;
-use std::rc::Rc;
 use errors::*;
-use std::fs::{File, OpenOptions};
-use std::path::Path;
+use vfs::{Vfs, Shm};
+use std::path::{Path, PathBuf};
 use std::convert::AsRef;
-use memmap::{Mmap, Protection};
-use fs2::FileExt;
-use std::cell::RefCell;
+use std::sync::Arc;
+use std::collections::BTreeMap;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{fence, Ordering, AtomicU32, AtomicU64};
+use byteorder::*;
+use checksum::Checksum;
+use integrity::Corruption;
+use wal::{PageNum, FrameNum};
 
-const MAGIC: u64 = 0x7dab6ca4b28afdee; 
-const INDEX_SIZE: u64 = 2 ^ 15;
+const MAGIC: u64 = 0x7dab6ca4b28afdee;
+/// The size of each region of the shared memory
+pub const REGION_SIZE: usize = 1 << 15;
+/// The number of frames covered by each segment of the hash table
+const SEGMENT_FRAMES: u32 = 4096;
+/// Twice as many slots as frames keeps the probe sequences short
+const HASH_SLOTS: u32 = SEGMENT_FRAMES * 2;
+/// The number of distinct snapshots that can be read at once
+pub const READ_MARKS: usize = 8;
+/// The offset of the first lock byte
+const LOCK_OFFSET: u64 = 4096;
+const WRITE_LOCK: u64 = LOCK_OFFSET;
+const CHECKPOINT_LOCK: u64 = LOCK_OFFSET + 1;
+const RECOVER_LOCK: u64 = LOCK_OFFSET + 2;
+/// The lock byte of the first read mark
+const READ_LOCK: u64 = LOCK_OFFSET + 3;
 
 pub struct WalIndex {
-    file: Rc<RefCell<File>>,
-    mmap: Mmap,
+    shm: Arc<Shm>,
+    path: PathBuf,
+    /// Region 0
+    header: *mut u8,
+}
+
+// The header is only accessed through volatile reads and writes and
+// atomics, and the `Shm` it points into is shared.
+unsafe impl Send for WalIndex { }
+unsafe impl Sync for WalIndex { }
+
+/// Checkpoint progress, stored after the two header copies
+#[repr(C)]
+struct CheckpointInfo {
+    /// The number of frames already copied to the database
+    n_backfill: AtomicU32,
+    /// The last frame of the snapshot read through each mark
+    read_marks: [AtomicU32; READ_MARKS],
+    /// How far the log is known to be synced to disk, packing the
+    /// low 32 bits of the epoch in the high 32 bits and the number of
+    /// synced frames in the low 32. Used by group commit.
+    synced: AtomicU64,
+}
+
+/// A reader's hold on a read mark, released on drop
+pub struct ReadMark {
+    lock: ByteLock,
+    mark: FrameNum,
+}
+
+/// Keeps new readers from taking read marks that depend on the log,
+/// so the log can restart. Released on drop.
+pub struct ReadMarksLock {
+    locks: Vec<ByteLock>,
 }
 
-struct Header {
+/// The log state shared between processes
+#[repr(C)]
+#[derive(Copy, Clone, Default, Debug, Eq, PartialEq)]
+pub struct IndexHeader {
     magic: u64,
+    pub epoch: u64,
+    /// Incremented every time the header is written
+    pub change_counter: u32,
+    /// The number of committed frames in the log
+    pub mx_frame: FrameNum,
+    /// The size of the database in pages after the last committed
+    /// transaction. Only meaningful if `mx_frame` is non-zero;
+    /// otherwise the database file has the right size.
+    pub db_size: PageNum,
+    pub page_size: u32,
+    pub salt: [u32; 2],
+    /// The cumulative checksum through the last committed frame
+    pub frame_checksum: [u32; 2],
+    checksum: [u32; 2],
+}
+
+struct Segment {
+    page_nums: *mut u32,
+    slots: *mut u16,
 }
 
 impl WalIndex {
-    pub fn new<P: AsRef<Path>>(p: P) -> Result<WalIndex> {
-        let file = OpenOptions::new()
-            .read(true).write(true).create(true)
-            .open(p.as_ref().with_extension("shm"))?;
-        file.allocate(INDEX_SIZE);
+    pub fn new<P: AsRef<Path>>(vfs: &Vfs, p: P) -> Result<WalIndex> {
+        let path = p.as_ref().with_extension("shm");
+        let shm: Arc<Shm> = Arc::from(vfs.open_shm(&path)?);
+        let header = shm.region(0, REGION_SIZE)?;
+
+        Ok(WalIndex {
+            shm: shm,
+            path: path,
+            header: header,
+        })
+    }
+
+    /// The lock held while appending to the log
+    pub fn write_lock(&self, busy: &BusyHandler) -> Result<ByteLock> {
+        ByteLock::new(&self.shm, WRITE_LOCK, LockKind::Exclusive, busy)
+    }
 
-        let mmap = Mmap::open(&file, Protection::ReadWrite)?;
+    /// The lock held while copying frames to the database
+    pub fn checkpoint_lock(&self, busy: &BusyHandler) -> Result<ByteLock> {
+        ByteLock::new(&self.shm, CHECKPOINT_LOCK, LockKind::Exclusive, busy)
+    }
 
-        let mut index = WalIndex {
-            file: Rc::new(RefCell::new(file)),
-            mmap: mmap
+    pub fn try_checkpoint_lock(&self) -> Result<Option<ByteLock>> {
+        ByteLock::try_new(&self.shm, CHECKPOINT_LOCK, LockKind::Exclusive)
+    }
+
+    /// The lock held while rebuilding the index from the log
+    pub fn recover_lock(&self, busy: &BusyHandler) -> Result<ByteLock> {
+        ByteLock::new(&self.shm, RECOVER_LOCK, LockKind::Exclusive, busy)
+    }
+
+    /// Whether another connection is rebuilding the index
+    pub fn is_recovering(&self) -> Result<bool> {
+        Ok(ByteLock::try_new(&self.shm, RECOVER_LOCK, LockKind::Shared)?.is_none())
+    }
+
+    fn header_ptr(&self, copy: usize) -> *mut IndexHeader {
+        let base = self.header as *mut IndexHeader;
+        unsafe { base.add(copy) }
+    }
+
+    /// Reads the header. Returns `None` if the index has not been
+    /// initialized or the read was torn by a concurrent writer.
+    pub fn read_header(&self) -> Option<IndexHeader> {
+        let (h0, h1) = unsafe {
+            let h0 = ptr::read_volatile(self.header_ptr(0));
+            fence(Ordering::SeqCst);
+            let h1 = ptr::read_volatile(self.header_ptr(1));
+            (h0, h1)
         };
 
-        index.init()?;
+        if h0 != h1 || h0.magic != MAGIC || h0.checksum != h0.compute_checksum() {
+            return None;
+        }
+
+        Some(h0)
+    }
+
+    /// Writes the header, bumping its change counter, and returns the
+    /// header as written.
+    pub fn write_header(&self, mut h: IndexHeader, lock: &ByteLock) -> IndexHeader {
+        h.magic = MAGIC;
+        h.change_counter = h.change_counter.wrapping_add(1);
+        h.checksum = h.compute_checksum();
+
+        // Readers read copy 0 then copy 1, so writing in the opposite
+        // order means any reader that sees the copies agree saw a
+        // complete header.
+        unsafe {
+            fence(Ordering::SeqCst);
+            ptr::write_volatile(self.header_ptr(1), h);
+            fence(Ordering::SeqCst);
+            ptr::write_volatile(self.header_ptr(0), h);
+            fence(Ordering::SeqCst);
+        }
+
+        h
+    }
+
+    pub fn n_backfill(&self) -> FrameNum {
+        checkpoint_info(self.header).n_backfill.load(Ordering::SeqCst)
+    }
+
+    pub fn set_n_backfill(&self, n: FrameNum, lock: &ByteLock) {
+        checkpoint_info(self.header).n_backfill.store(n, Ordering::SeqCst)
+    }
+
+    /// Whether the first `frames` frames of `epoch` are known to be
+    /// synced to the log. Syncs are only tracked for the latest epoch
+    /// synced, so this says nothing about earlier ones, whose frames
+    /// may have been checkpointed without a sync.
+    pub fn is_synced(&self, epoch: u64, frames: FrameNum) -> bool {
+        let synced = checkpoint_info(self.header).synced.load(Ordering::SeqCst);
+        let (synced_epoch, synced_frames) = unpack(synced);
+        synced_epoch == epoch as u32 && synced_frames >= frames
+    }
+
+    /// Records that the first `frames` frames of `epoch` are on disk
+    pub fn set_synced(&self, epoch: u64, frames: FrameNum) {
+        checkpoint_info(self.header).synced
+            .fetch_max(pack(epoch as u32, frames), Ordering::SeqCst);
+    }
+
+    /// Forgets what was synced, for when the index is rebuilt
+    pub fn reset_synced(&self, lock: &ByteLock) {
+        checkpoint_info(self.header).synced.store(0, Ordering::SeqCst);
+    }
+
+    /// Takes a read mark for a snapshot ending at frame `mark`,
+    /// sharing one with other readers of the same snapshot if
+    /// possible. Returns `None` if every mark is in use for other
+    /// snapshots.
+    pub fn begin_read(&self, mark: FrameNum) -> Result<Option<ReadMark>> {
+        let info = checkpoint_info(self.header);
+
+        // Prefer sharing a mark. Once the byte is locked shared
+        // nobody can change the mark, so check it again.
+        for slot in 0..READ_MARKS {
+            if info.read_marks[slot].load(Ordering::SeqCst) != mark {
+                continue;
+            }
+            if let Some(lock) = ByteLock::try_new(&self.shm, read_lock(slot), LockKind::Shared)? {
+                if info.read_marks[slot].load(Ordering::SeqCst) == mark {
+                    return Ok(Some(ReadMark {
+                        lock: lock,
+                        mark: mark,
+                    }));
+                }
+            }
+        }
+
+        // Then fall back to one nobody is using
+        for slot in 0..READ_MARKS {
+            if let Some(mut lock) = ByteLock::try_new(&self.shm, read_lock(slot), LockKind::Exclusive)? {
+                info.read_marks[slot].store(mark, Ordering::SeqCst);
+                lock.downgrade()?;
+                return Ok(Some(ReadMark {
+                    lock: lock,
+                    mark: mark,
+                }));
+            }
+        }
 
-        Ok(index)
+        Ok(None)
     }
 
-    fn init(&mut self) -> Result<()> {
-        self.with_header_mut(&mut |header| {
-            let reinit = header.magic != MAGIC;
-            if reinit {
-                header.magic = MAGIC;
+    /// The oldest snapshot still being read, if any
+    pub fn oldest_read_mark(&self) -> Result<Option<FrameNum>> {
+        let info = checkpoint_info(self.header);
+        let mut oldest = None;
+        for slot in 0..READ_MARKS {
+            if ByteLock::try_new(&self.shm, read_lock(slot), LockKind::Exclusive)?.is_some() {
+                continue;
             }
+            let mark = info.read_marks[slot].load(Ordering::SeqCst);
+            oldest = Some(oldest.map_or(mark, |o: FrameNum| o.min(mark)));
+        }
 
-            Ok(())
+        Ok(oldest)
+    }
+
+    /// Locks every read mark not in use, so that no new reader can
+    /// start reading the log. Returns `None` if a reader is still
+    /// using the log. Readers with mark 0 don't use the log and are
+    /// allowed to continue.
+    pub fn lock_read_marks(&self) -> Result<Option<ReadMarksLock>> {
+        let info = checkpoint_info(self.header);
+        let mut lock = ReadMarksLock {
+            locks: Vec::new(),
+        };
+
+        for slot in 0..READ_MARKS {
+            if let Some(l) = ByteLock::try_new(&self.shm, read_lock(slot), LockKind::Exclusive)? {
+                lock.locks.push(l);
+                continue;
+            }
+
+            // Holding the mark shared keeps it at 0 while new readers
+            // join in, and fails if it is held exclusively by a
+            // reader that is about to change it
+            match ByteLock::try_new(&self.shm, read_lock(slot), LockKind::Shared)? {
+                Some(ref l) if info.read_marks[slot].load(Ordering::SeqCst) != 0 => {
+                    return Ok(None);
+                }
+                Some(l) => lock.locks.push(l),
+                None => return Ok(None),
+            }
+        }
+
+        Ok(Some(lock))
+    }
+
+    /// Locks every read mark exclusively, consulting `busy` while
+    /// readers hold them, so nobody reads the index while it is
+    /// rebuilt
+    pub fn lock_all_read_marks(&self, busy: &BusyHandler) -> Result<ReadMarksLock> {
+        let mut lock = ReadMarksLock {
+            locks: Vec::new(),
+        };
+
+        for slot in 0..READ_MARKS {
+            lock.locks.push(ByteLock::new(&self.shm, read_lock(slot), LockKind::Exclusive, busy)?);
+        }
+
+        Ok(lock)
+    }
+
+    /// Records that frames `first..` hold `pages`. Any entries for
+    /// those frames left by an abandoned transaction are discarded.
+    pub fn append(&self, first: FrameNum, pages: &[PageNum], lock: &ByteLock) -> Result<()> {
+        for (i, &page_num) in pages.iter().enumerate() {
+            let frame = first + i as u32;
+            let n = frame / SEGMENT_FRAMES;
+            let seg = self.segment(n)?;
+            let idx = frame % SEGMENT_FRAMES;
+            if i == 0 || idx == 0 {
+                seg.truncate(idx);
+            }
+            if !seg.insert(page_num, idx) {
+                bail!(ErrorKind::Corrupt(Corruption {
+                    file: self.path.clone(),
+                    offset: slots_offset(n),
+                    frame: Some(frame),
+                    page: Some(page_num),
+                    expected: "a free hash slot".to_string(),
+                    found: "every slot in use".to_string(),
+                }));
+            }
+        }
+
+        fence(Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Finds the last frame before `mx_frame` that holds `page_num`
+    pub fn find_frame(&self, page_num: PageNum, mx_frame: FrameNum) -> Result<Option<FrameNum>> {
+        if mx_frame == 0 {
+            return Ok(None);
+        }
+
+        let last = (mx_frame - 1) / SEGMENT_FRAMES;
+        for n in (0..last + 1).rev() {
+            let seg = self.segment(n)?;
+            let limit = (mx_frame - n * SEGMENT_FRAMES).min(SEGMENT_FRAMES);
+            if let Some(idx) = seg.find(page_num, limit) {
+                return Ok(Some(n * SEGMENT_FRAMES + idx));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Describes what is wrong with the header, for when
+    /// `read_header` fails while the write lock is held and no writer
+    /// can be tearing it
+    pub fn header_corruption(&self, lock: &ByteLock) -> Corruption {
+        let (h0, h1) = unsafe {
+            (ptr::read_volatile(self.header_ptr(0)), ptr::read_volatile(self.header_ptr(1)))
+        };
+
+        let corruption = |offset: usize, expected: String, found: String| Corruption {
+            file: self.path.clone(),
+            offset: offset as u64,
+            frame: None,
+            page: None,
+            expected: expected,
+            found: found,
+        };
+        if h0.magic != MAGIC {
+            corruption(0, format!("magic {:016x}", MAGIC), format!("{:016x}", h0.magic))
+        } else if h0.checksum != h0.compute_checksum() {
+            let expected = h0.compute_checksum();
+            corruption(0, format!("header checksum {}", Checksum(expected[0], expected[1])),
+                       format!("{}", Checksum(h0.checksum[0], h0.checksum[1])))
+        } else {
+            corruption(mem::size_of::<IndexHeader>(),
+                       "a second copy of the header".to_string(),
+                       "a different header".to_string())
+        }
+    }
+
+    /// Checks that the checkpoint info agrees with `h`
+    pub fn check_checkpoint_info(&self, h: &IndexHeader) -> Option<Corruption> {
+        let n_backfill = self.n_backfill();
+        if n_backfill <= h.mx_frame {
+            return None;
+        }
+
+        Some(Corruption {
+            file: self.path.clone(),
+            offset: 2 * mem::size_of::<IndexHeader>() as u64,
+            frame: None,
+            page: None,
+            expected: format!("at most {} frames backfilled", h.mx_frame),
+            found: format!("{} frames", n_backfill),
         })
     }
 
-    fn with_header_mut<R>(&mut self, f: &mut FnMut(&mut Header) -> Result<R>) -> Result<R> {
-        let _lock = self.write_lock()?;
-        let header: &mut Header = unsafe { &mut * (self.mmap.mut_ptr() as *mut Header) };
-        f(header)
+    /// Checks that the hash table records `frame` as holding
+    /// `page_num`, and that looking the page up finds it
+    pub fn check_frame(&self, frame: FrameNum, page_num: PageNum) -> Result<Option<Corruption>> {
+        let n = frame / SEGMENT_FRAMES;
+        let idx = frame % SEGMENT_FRAMES;
+        let seg = self.segment(n)?;
+
+        let recorded = seg.page_num(idx);
+        if recorded != page_num {
+            return Ok(Some(Corruption {
+                file: self.path.clone(),
+                offset: page_nums_offset(n) + idx as u64 * mem::size_of::<u32>() as u64,
+                frame: Some(frame),
+                page: Some(page_num),
+                expected: format!("page {}", page_num),
+                found: format!("page {}", recorded),
+            }));
+        }
+
+        let found = self.find_frame(page_num, frame + 1)?;
+        if found != Some(frame) {
+            return Ok(Some(Corruption {
+                file: self.path.clone(),
+                offset: slots_offset(n),
+                frame: Some(frame),
+                page: Some(page_num),
+                expected: format!("a hash slot for frame {}", frame),
+                found: match found {
+                    Some(f) => format!("frame {}", f),
+                    None => "no slot".to_string(),
+                },
+            }));
+        }
+
+        Ok(None)
+    }
+
+    /// Maps every page in the frames before `mx_frame` to its last frame
+    pub fn pages(&self, mx_frame: FrameNum) -> Result<BTreeMap<PageNum, FrameNum>> {
+        let mut pages = BTreeMap::new();
+        for frame in 0..mx_frame {
+            let seg = self.segment(frame / SEGMENT_FRAMES)?;
+            pages.insert(seg.page_num(frame % SEGMENT_FRAMES), frame);
+        }
+
+        Ok(pages)
+    }
+
+    fn segment(&self, n: u32) -> Result<Segment> {
+        let base = self.shm.region(n + 1, REGION_SIZE)?;
+        let slots = (slots_offset(n) - page_nums_offset(n)) as isize;
+        Ok(Segment {
+            page_nums: base as *mut u32,
+            slots: unsafe { base.offset(slots) as *mut u16 },
+        })
     }
+}
+
+impl ReadMark {
+    /// The last frame of the snapshot
+    pub fn mark(&self) -> FrameNum {
+        self.mark
+    }
+}
+
+
+fn checkpoint_info<'a>(header: *mut u8) -> &'a CheckpointInfo {
+    let offset = 2 * mem::size_of::<IndexHeader>() as isize;
+    unsafe { &*(header.offset(offset) as *const CheckpointInfo) }
+}
+
+/// The offset in the file of the page numbers of segment `n`
+fn page_nums_offset(n: u32) -> u64 {
+    (n as u64 + 1) * REGION_SIZE as u64
+}
+
+/// The offset in the file of the hash slots of segment `n`
+fn slots_offset(n: u32) -> u64 {
+    page_nums_offset(n) + SEGMENT_FRAMES as u64 * mem::size_of::<u32>() as u64
+}
+
+fn pack(hi: u32, lo: u32) -> u64 {
+    (hi as u64) << 32 | lo as u64
+}
+
+fn unpack(n: u64) -> (u32, u32) {
+    ((n >> 32) as u32, n as u32)
+}
+
+fn read_lock(slot: usize) -> u64 {
+    READ_LOCK + slot as u64
+}
+
+impl IndexHeader {
+    fn compute_checksum(&self) -> [u32; 2] {
+        let mut buf = Vec::with_capacity(44);
+        buf.write_u64::<LittleEndian>(self.magic).expect("vec");
+        buf.write_u64::<LittleEndian>(self.epoch).expect("vec");
+        buf.write_u32::<LittleEndian>(self.change_counter).expect("vec");
+        buf.write_u32::<LittleEndian>(self.mx_frame).expect("vec");
+        buf.write_u32::<LittleEndian>(self.db_size).expect("vec");
+        buf.write_u32::<LittleEndian>(self.page_size).expect("vec");
+        buf.write_u32::<LittleEndian>(self.salt[0]).expect("vec");
+        buf.write_u32::<LittleEndian>(self.salt[1]).expect("vec");
+        buf.write_u32::<LittleEndian>(self.frame_checksum[0]).expect("vec");
+        buf.write_u32::<LittleEndian>(self.frame_checksum[1]).expect("vec");
+        let Checksum(s0, s1) = Checksum::default().update(&buf);
+        [s0, s1]
+    }
+}
+
+fn hash(page_num: PageNum) -> u32 {
+    page_num.wrapping_mul(383) & (HASH_SLOTS - 1)
+}
+
+/// Slots hold the index of a frame within the segment, plus one, so
+/// that zero means empty. Entries are only ever added in frame order,
+/// so removing the entries for trailing frames never breaks the
+/// probe sequence of an earlier entry.
+impl Segment {
+    fn page_num(&self, idx: u32) -> PageNum {
+        unsafe { ptr::read_volatile(self.page_nums.offset(idx as isize)) }
+    }
+
+    fn slot(&self, s: u32) -> u32 {
+        unsafe { ptr::read_volatile(self.slots.offset(s as isize)) as u32 }
+    }
+
+    fn set_slot(&self, s: u32, v: u32) {
+        unsafe { ptr::write_volatile(self.slots.offset(s as isize), v as u16) }
+    }
+
+    /// Returns false if there is no free slot, which can only happen
+    /// if the shared memory was corrupted
+    fn insert(&self, page_num: PageNum, idx: u32) -> bool {
+        unsafe { ptr::write_volatile(self.page_nums.offset(idx as isize), page_num) }
+        let mut s = hash(page_num);
+        for _ in 0..HASH_SLOTS {
+            if self.slot(s) == 0 {
+                self.set_slot(s, idx + 1);
+                return true;
+            }
+            s = (s + 1) & (HASH_SLOTS - 1);
+        }
+        false
+    }
+
+    /// Removes the entries for frames at or after `idx`
+    fn truncate(&self, idx: u32) {
+        for s in 0..HASH_SLOTS {
+            let v = self.slot(s);
+            if v > idx {
+                self.set_slot(s, 0);
+            }
+        }
+    }
+
+    /// Finds the last frame before `limit`, which is at most
+    /// `SEGMENT_FRAMES`, that holds `page_num`
+    fn find(&self, page_num: PageNum, limit: u32) -> Option<u32> {
+        let mut found = None;
+        let mut s = hash(page_num);
+        for _ in 0..HASH_SLOTS {
+            let v = self.slot(s);
+            if v == 0 {
+                break;
+            }
+            let idx = v - 1;
+            if idx < limit && self.page_num(idx) == page_num {
+                found = Some(found.map_or(idx, |f: u32| f.max(idx)));
+            }
+            s = (s + 1) & (HASH_SLOTS - 1);
+        }
 
-    pub fn write_lock(&self) -> Result<ExLock> {
-        ExLock::new(self.file.clone())
+        found
     }
 }
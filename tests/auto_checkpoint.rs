@@ -0,0 +1,84 @@
+//! The wal hook and automatic checkpoints after commits
+
+extern crate btrs;
+
+mod common;
+
+use btrs::lock::BusyHandler;
+use btrs::mem_vfs::MemVfs;
+use btrs::units::PageSize;
+use btrs::wabl::*;
+use btrs::wal::{CheckpointMode, Wal};
+use common::*;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The number of whole pages in the database file
+fn db_file_pages(vfs: &MemVfs) -> u64 {
+    file_len(vfs, "db") / PAGE_SIZE as u64
+}
+
+#[test]
+fn hook_and_threshold() {
+    let vfs = MemVfs::new();
+    let mut w = try_open(&vfs).unwrap();
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    {
+        let seen = seen.clone();
+        w.set_wal_hook(Some(Box::new(move |frames| seen.lock().unwrap().push(frames))));
+    }
+    // Each commit logs page 0 and the page written
+    w.set_auto_checkpoint(AutoCheckpoint::Frames(6));
+
+    for n in 1..3 {
+        let mut tx = w.begin_write().unwrap();
+        tx.write_page(n, page(0)).unwrap();
+        tx.commit().unwrap();
+    }
+    assert_eq!(*seen.lock().unwrap(), vec![2, 4]);
+    assert_eq!(db_file_pages(&vfs), 1);
+
+    // Crossing the threshold copies the log to the database, and the
+    // next commit starts the log over
+    for n in 3..5 {
+        let mut tx = w.begin_write().unwrap();
+        tx.write_page(n, page(0)).unwrap();
+        tx.commit().unwrap();
+    }
+    assert_eq!(*seen.lock().unwrap(), vec![2, 4, 6, 2]);
+    assert_eq!(db_file_pages(&vfs), 4);
+
+    // Without a hook commits carry on as before
+    w.set_wal_hook(None);
+    let mut tx = w.begin_write().unwrap();
+    tx.write_page(5, page(0)).unwrap();
+    tx.commit().unwrap();
+    assert_eq!(seen.lock().unwrap().len(), 4);
+    assert!(w.check_integrity().unwrap().is_empty());
+}
+
+#[test]
+fn skipped_while_checkpointing() {
+    let vfs = MemVfs::new();
+    let mut w = try_open(&vfs).unwrap();
+    w.set_auto_checkpoint(AutoCheckpoint::Frames(2));
+    w.set_busy_handler(BusyHandler::Timeout(Duration::from_millis(10)));
+
+    // The automatic checkpoint can't run while another connection is
+    // checkpointing, but the transaction is committed all the same
+    let other = Wal::new(Arc::new(vfs.clone()), PATH, PageSize::new(PAGE_SIZE)).unwrap();
+    let checkpoint = other.begin_checkpoint(CheckpointMode::Passive).unwrap();
+    let mut tx = w.begin_write().unwrap();
+    tx.write_page(1, page(0)).unwrap();
+    tx.commit().unwrap();
+    assert_eq!(w.begin_read().unwrap().db_size().unwrap(), 2);
+    assert_eq!(db_file_pages(&vfs), 1);
+
+    // Once it is done, the next commit checkpoints
+    drop(checkpoint);
+    let mut tx = w.begin_write().unwrap();
+    tx.write_page(2, page(0)).unwrap();
+    tx.commit().unwrap();
+    assert_eq!(db_file_pages(&vfs), 3);
+    assert!(w.check_integrity().unwrap().is_empty());
+}
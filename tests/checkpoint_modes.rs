@@ -0,0 +1,81 @@
+//! What each checkpoint mode does while a reader holds on to an old
+//! snapshot, and after it leaves
+
+extern crate btrs;
+
+mod common;
+
+use btrs::errors::*;
+use btrs::lock::BusyHandler;
+use btrs::mem_vfs::MemVfs;
+use btrs::wabl::*;
+use btrs::wal::{CheckpointMode, CheckpointResult};
+use common::{file_len, read};
+use std::time::Duration;
+
+fn open(vfs: &MemVfs) -> Wabl {
+    let mut w = common::open(vfs);
+    w.set_busy_handler(BusyHandler::Timeout(Duration::from_millis(10)));
+    w
+}
+
+/// Commits page `n`, which logs it and page 0
+fn write(w: &Wabl, n: u32) {
+    common::write(w, n, n as u8);
+}
+
+fn assert_busy(r: Result<CheckpointResult>) {
+    match r {
+        Err(Error(ErrorKind::Busy, _)) => { }
+        r => panic!("expected Busy, got {:?}", r),
+    }
+}
+
+#[test]
+fn pinned_reader() {
+    let vfs = MemVfs::new();
+    let w = open(&vfs);
+    let r = open(&vfs);
+    write(&w, 1);
+    write(&w, 2);
+    let mut reader = r.begin_read().unwrap();
+    write(&w, 3);
+
+    // Passive copies what the reader doesn't need and returns
+    let result = w.checkpoint(CheckpointMode::Passive).unwrap();
+    assert_eq!((result.log_frames, result.backfilled_frames), (6, 4));
+
+    // The others wait for the reader, and give up
+    assert_busy(w.checkpoint(CheckpointMode::Full));
+    assert_busy(w.checkpoint(CheckpointMode::Restart));
+    assert_busy(w.checkpoint(CheckpointMode::Truncate));
+    assert_eq!(reader.db_size().unwrap(), 3);
+    assert_eq!(reader.read_page(2).unwrap().buf()[0], 2);
+    drop(reader);
+
+    // Once it's gone, restarting copies everything and the next
+    // commit starts at the beginning of the log
+    let result = w.checkpoint(CheckpointMode::Restart).unwrap();
+    assert_eq!((result.log_frames, result.backfilled_frames), (6, 6));
+    let len = file_len(&vfs, "wal");
+    write(&w, 4);
+    let result = w.checkpoint(CheckpointMode::Passive).unwrap();
+    assert_eq!((result.log_frames, result.backfilled_frames), (2, 2));
+    assert_eq!(file_len(&vfs, "wal"), len);
+
+    // Truncating also empties the log file. Everything was copied,
+    // so this commit started over at the beginning too.
+    write(&w, 5);
+    let result = w.checkpoint(CheckpointMode::Truncate).unwrap();
+    assert_eq!((result.log_frames, result.backfilled_frames), (2, 2));
+    assert!(file_len(&vfs, "wal") < len);
+    write(&w, 6);
+    let result = w.checkpoint(CheckpointMode::Full).unwrap();
+    assert_eq!((result.log_frames, result.backfilled_frames), (2, 2));
+
+    assert_eq!(r.begin_read().unwrap().db_size().unwrap(), 7);
+    for n in 1..7 {
+        assert_eq!(read(&r, n), n as u8);
+    }
+    assert!(w.check_integrity().unwrap().is_empty());
+}
@@ -0,0 +1,57 @@
+//! Helpers shared by the integration tests. Each test uses its own
+//! in-memory `Vfs`, so they can all keep the database at `PATH`.
+
+#![allow(dead_code)]
+
+use btrs::errors::*;
+use btrs::page::Page;
+use btrs::units::PageSize;
+use btrs::vfs::Vfs;
+use btrs::wabl::*;
+use btrs::wal::PageNum;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub const PAGE_SIZE: u32 = 4096;
+pub const PATH: &str = "test";
+
+/// The database's file with extension `ext`: "db", "wal" or "shm"
+pub fn path(ext: &str) -> PathBuf {
+    Path::new(PATH).with_extension(ext)
+}
+
+/// The length of the database's file with extension `ext`
+pub fn file_len<V: Vfs>(vfs: &V, ext: &str) -> u64 {
+    vfs.open(&path(ext)).unwrap().len().unwrap()
+}
+
+pub fn try_open<V: Vfs + Clone + 'static>(vfs: &V) -> Result<Wabl> {
+    Wabl::new(Arc::new(vfs.clone()), &PATH)
+}
+
+/// Opens the database with automatic checkpoints off, so that only
+/// the test checkpoints
+pub fn open<V: Vfs + Clone + 'static>(vfs: &V) -> Wabl {
+    let mut w = try_open(vfs).unwrap();
+    w.set_auto_checkpoint(AutoCheckpoint::Disabled);
+    w
+}
+
+/// A page starting with `byte`, and zeroed otherwise
+pub fn page(byte: u8) -> Page {
+    let mut page = Page::new(PageSize::new(PAGE_SIZE));
+    page.buf_mut()[0] = byte;
+    page
+}
+
+/// Commits page `n`, starting with `byte`
+pub fn write(w: &Wabl, n: PageNum, byte: u8) {
+    let mut tx = w.begin_write().unwrap();
+    tx.write_page(n, page(byte)).unwrap();
+    tx.commit().unwrap();
+}
+
+/// The first byte of page `n`
+pub fn read(w: &Wabl, n: PageNum) -> u8 {
+    w.begin_read().unwrap().read_page(n).unwrap().buf()[0]
+}
@@ -0,0 +1,202 @@
+//! Runs a workload against `FaultVfs`, cutting the power or failing
+//! a syscall at every I/O operation in turn, and checks that exactly
+//! the committed transactions survive.
+
+extern crate btrs;
+extern crate libc;
+
+mod common;
+
+use btrs::errors::*;
+use btrs::fault_vfs::FaultVfs;
+use btrs::page::Page;
+use btrs::units::PageSize;
+use btrs::wabl::*;
+use btrs::wal::{CheckpointMode, PageNum};
+use common::{try_open, PAGE_SIZE};
+use std::collections::BTreeMap;
+
+const TRANSACTIONS: u8 = 12;
+
+/// The expected state of the database: its size, and the byte each
+/// page is filled with for the pages whose contents are known
+#[derive(Clone, Debug, PartialEq)]
+struct Model {
+    size: PageNum,
+    pages: BTreeMap<PageNum, u8>,
+}
+
+/// How far the workload got before failing
+#[derive(Default)]
+struct Progress {
+    committed: u8,
+    /// Whether it failed during a commit, in which case that
+    /// transaction may or may not have survived
+    in_commit: bool,
+}
+
+/// Transaction `t` shrinks the database, or writes pages filled with
+/// `t`
+fn apply(t: u8, model: &mut Model) {
+    if t.is_multiple_of(5) {
+        model.size = 4;
+        model.pages = model.pages.iter()
+            .filter(|&(&p, _)| p < 4)
+            .map(|(&p, &b)| (p, b))
+            .collect();
+        return;
+    }
+
+    for p in pages(t) {
+        model.pages.insert(p, t);
+        model.size = model.size.max(p + 1);
+    }
+}
+
+fn pages(t: u8) -> Vec<PageNum> {
+    let t = t as PageNum;
+    (0..1 + t % 4).map(|k| 1 + (t * 3 + k * 5) % 9).collect()
+}
+
+/// The state after each number of transactions
+fn models() -> Vec<Model> {
+    // A new database has only page 0, holding the header
+    let mut models = vec![Model { size: 1, pages: BTreeMap::new() }];
+    for t in 1..TRANSACTIONS + 1 {
+        let mut m = models.last().unwrap().clone();
+        apply(t, &mut m);
+        models.push(m);
+    }
+    models
+}
+
+fn filled(b: u8) -> Page {
+    let mut page = Page::new(PageSize::new(PAGE_SIZE));
+    for x in page.buf_mut() {
+        *x = b;
+    }
+    page
+}
+
+fn open(vfs: &FaultVfs) -> Result<Wabl> {
+    let mut w = try_open(vfs)?;
+    w.set_auto_checkpoint(AutoCheckpoint::Frames(6));
+    Ok(w)
+}
+
+fn run(vfs: &FaultVfs, progress: &mut Progress) -> Result<()> {
+    let w = open(vfs)?;
+    let modes = [CheckpointMode::Passive, CheckpointMode::Full,
+                 CheckpointMode::Restart, CheckpointMode::Truncate];
+
+    for t in 1..TRANSACTIONS + 1 {
+        let mut tx = w.begin_read()?.begin_write()?;
+        if t.is_multiple_of(5) {
+            tx.set_db_size(4)?;
+        } else {
+            for p in pages(t) {
+                tx.write_page(p, filled(t))?;
+            }
+        }
+
+        progress.in_commit = true;
+        tx.commit()?;
+        progress.in_commit = false;
+        progress.committed = t;
+
+        if t.is_multiple_of(3) {
+            w.checkpoint(modes[(t / 3) as usize % modes.len()])?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether the database is in the state `model`
+fn matches(w: &Wabl, model: &Model) -> Result<bool> {
+    let mut tx = w.begin_read()?;
+    if tx.db_size()? != model.size {
+        return Ok(false);
+    }
+    for (&p, &b) in &model.pages {
+        if tx.read_page(p)?.buf().iter().any(|&x| x != b) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Checks that the database holds the committed transactions, and
+/// that it can still be written
+fn check(vfs: &FaultVfs, progress: &Progress, models: &[Model], what: &str) {
+    let w = open(vfs).expect(what);
+    let committed = progress.committed as usize;
+    let survived = if matches(&w, &models[committed]).expect(what) {
+        committed
+    } else if progress.in_commit && matches(&w, &models[committed + 1]).expect(what) {
+        committed + 1
+    } else {
+        panic!("{}: lost committed transactions, or kept uncommitted ones", what);
+    };
+
+    let mut model = models[survived].clone();
+    let mut tx = w.begin_read().expect(what).begin_write().expect(what);
+    tx.write_page(1, filled(0xff)).expect(what);
+    tx.commit().expect(what);
+    w.checkpoint(CheckpointMode::Passive).expect(what);
+    model.pages.insert(1, 0xff);
+    model.size = model.size.max(2);
+    assert!(matches(&w, &model).expect(what), "{}: can't write after recovery", what);
+}
+
+/// The number of I/O operations in a run without faults
+fn count_ops() -> u64 {
+    let vfs = FaultVfs::new(0);
+    run(&vfs, &mut Progress::default()).unwrap();
+    vfs.ops()
+}
+
+#[test]
+fn workload_without_faults() {
+    let vfs = FaultVfs::new(0);
+    let models = models();
+    let mut progress = Progress::default();
+    run(&vfs, &mut progress).unwrap();
+    assert_eq!(progress.committed, TRANSACTIONS);
+    vfs.crash();
+    check(&vfs, &progress, &models, "no faults");
+}
+
+#[test]
+fn power_loss_at_every_operation() {
+    let models = models();
+    for n in 0..count_ops() {
+        for seed in 0..3 {
+            let vfs = FaultVfs::new(n as u32 * 3 + seed);
+            vfs.power_off_after(n);
+            let mut progress = Progress::default();
+            assert!(run(&vfs, &mut progress).is_err());
+            vfs.crash();
+            check(&vfs, &progress, &models,
+                  &format!("power off after {} operations, seed {}", n, seed));
+        }
+    }
+}
+
+#[test]
+fn failed_operation_at_every_operation() {
+    let models = models();
+    for n in 0..count_ops() {
+        for &errno in &[libc::EIO, libc::ENOSPC] {
+            let vfs = FaultVfs::new(n as u32);
+            vfs.fail_after(None, n, errno);
+            let mut progress = Progress::default();
+            if run(&vfs, &mut progress).is_ok() {
+                // The failure was retried or didn't matter
+                continue;
+            }
+            check(&vfs, &progress, &models,
+                  &format!("errno {} after {} operations", errno, n));
+        }
+    }
+}
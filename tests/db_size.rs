@@ -0,0 +1,33 @@
+//! The size of the database recorded on commit frames
+
+extern crate btrs;
+
+mod common;
+
+use btrs::mem_vfs::MemVfs;
+use btrs::units::PageSize;
+use btrs::wal::Wal;
+use common::*;
+use std::sync::Arc;
+
+#[test]
+fn shrinking_drops_dirty_pages() {
+    let vfs = MemVfs::new();
+    let wal = Wal::new(Arc::new(vfs.clone()), PATH, PageSize::new(PAGE_SIZE)).unwrap();
+    let mut tx = wal.begin_write().unwrap();
+    tx.set_db_size(1);
+    tx.write_page(1, page(1)).unwrap();
+    tx.write_page(5, page(5)).unwrap();
+    assert_eq!(tx.db_size(), Some(6));
+
+    // Pages past the new end are gone, within the transaction and
+    // after it commits
+    tx.set_db_size(2);
+    assert!(tx.read_page(5).unwrap().is_none());
+    tx.commit().unwrap();
+
+    let r = wal.begin_read().unwrap();
+    assert_eq!(r.db_size(), Some(2));
+    assert_eq!(r.read_page(1).unwrap().unwrap().buf()[0], 1);
+    assert!(r.read_page(5).unwrap().is_none());
+}
@@ -0,0 +1,171 @@
+//! Checks that failures callers may want to handle come back as the
+//! right `ErrorKind`
+
+extern crate btrs;
+
+mod common;
+
+use btrs::errors::*;
+use btrs::lock::{BusyHandler, LockKind};
+use btrs::mem_vfs::{MemVfs, MAX_FILE_SIZE};
+use btrs::page::Page;
+use btrs::units::PageSize;
+use btrs::vfs::Vfs;
+use btrs::wal::Wal;
+use common::*;
+use std::sync::Arc;
+
+/// Replaces the database file with `data`
+fn write_db(vfs: &MemVfs, data: &[u8]) {
+    let file = vfs.open(&path("db")).unwrap();
+    file.set_len(0).unwrap();
+    file.write_at(data, 0).unwrap();
+}
+
+#[test]
+fn not_a_database() {
+    let vfs = MemVfs::new();
+    write_db(&vfs, b"this is a text file, not a database\n");
+    match try_open(&vfs) {
+        Err(Error(ErrorKind::NotADatabase, _)) => { }
+        r => panic!("expected NotADatabase, got {:?}", r.err()),
+    }
+
+    // A header cut short
+    let vfs = MemVfs::new();
+    drop(try_open(&vfs).unwrap());
+    write_db(&vfs, &[0xc8]);
+    match try_open(&vfs) {
+        Err(Error(ErrorKind::NotADatabase, _)) => { }
+        r => panic!("expected NotADatabase, got {:?}", r.err()),
+    }
+}
+
+#[test]
+fn page_size_mismatch() {
+    let vfs = MemVfs::new();
+    let w = try_open(&vfs).unwrap();
+    let mut tx = w.begin_read().unwrap().begin_write().unwrap();
+    match tx.write_page(1, Page::new(PageSize::new(PAGE_SIZE * 2))) {
+        Err(Error(ErrorKind::PageSizeMismatch(PAGE_SIZE, found), _)) => {
+            assert_eq!(found, PAGE_SIZE * 2);
+        }
+        r => panic!("expected PageSizeMismatch, got {:?}", r.err()),
+    }
+}
+
+#[test]
+fn page_out_of_range() {
+    let vfs = MemVfs::new();
+    let w = try_open(&vfs).unwrap();
+    let mut tx = w.begin_read().unwrap().begin_write().unwrap();
+    tx.write_page(2, page(0)).unwrap();
+    tx.commit().unwrap();
+
+    let mut tx = w.begin_read().unwrap();
+    match tx.read_page(3) {
+        Err(Error(ErrorKind::PageOutOfRange(3, 3), _)) => { }
+        r => panic!("expected PageOutOfRange, got {:?}", r.err()),
+    }
+}
+
+#[test]
+fn full() {
+    let vfs = MemVfs::new();
+    let w = try_open(&vfs).unwrap();
+    let last = (MAX_FILE_SIZE / PAGE_SIZE as u64) as u32;
+    let mut tx = w.begin_read().unwrap().begin_write().unwrap();
+    tx.write_page(last, page(0)).unwrap();
+    tx.commit().unwrap();
+    match w.checkpoint(btrs::wal::CheckpointMode::Passive) {
+        Err(Error(ErrorKind::Full, _)) => { }
+        r => panic!("expected Full, got {:?}", r.err()),
+    }
+
+    // No page can come after the last page number
+    let mut tx = w.begin_read().unwrap().begin_write().unwrap();
+    match tx.write_page(!0, page(0)) {
+        Err(Error(ErrorKind::Full, _)) => { }
+        r => panic!("expected Full, got {:?}", r.err()),
+    }
+}
+
+#[test]
+fn empty_commit() {
+    let vfs = MemVfs::new();
+    let wal = Wal::new(Arc::new(vfs.clone()), PATH, PageSize::new(PAGE_SIZE)).unwrap();
+    let mut tx = wal.begin_write().unwrap();
+    tx.write_page(2, page(0)).unwrap();
+    tx.set_db_size(0);
+    match tx.commit() {
+        Err(Error(ErrorKind::PageOutOfRange(0, 0), _)) => { }
+        r => panic!("expected PageOutOfRange, got {:?}", r.err()),
+    }
+}
+
+#[test]
+fn unknown_db_size() {
+    let vfs = MemVfs::new();
+    let wal = Wal::new(Arc::new(vfs.clone()), PATH, PageSize::new(PAGE_SIZE)).unwrap();
+    let mut tx = wal.begin_write().unwrap();
+    tx.write_page(2, page(0)).unwrap();
+    assert_eq!(tx.db_size(), None);
+    match tx.commit() {
+        Err(Error(ErrorKind::UnknownDbSize, _)) => { }
+        r => panic!("expected UnknownDbSize, got {:?}", r.err()),
+    }
+
+    // Once the size is known, writes past the end grow it
+    let mut tx = wal.begin_write().unwrap();
+    tx.set_db_size(5);
+    tx.write_page(2, page(0)).unwrap();
+    assert_eq!(tx.db_size(), Some(5));
+    tx.write_page(6, page(0)).unwrap();
+    assert_eq!(tx.db_size(), Some(7));
+    tx.commit().unwrap();
+    assert_eq!(wal.begin_write().unwrap().db_size(), Some(7));
+}
+
+#[test]
+fn busy_database_file() {
+    let vfs = MemVfs::new();
+    let mut w = try_open(&vfs).unwrap();
+    w.set_busy_handler(BusyHandler::Callback(Arc::new(|_| false)));
+
+    // Another connection creating the database holds its file locked
+    let file = vfs.open(&path("db")).unwrap();
+    file.lock(LockKind::Exclusive).unwrap();
+    match w.check_integrity() {
+        Err(Error(ErrorKind::Busy, _)) => { }
+        r => panic!("expected Busy, got {:?}", r.err()),
+    }
+    file.unlock().unwrap();
+    assert!(w.check_integrity().unwrap().is_empty());
+}
+
+#[test]
+fn busy_snapshot() {
+    let vfs = MemVfs::new();
+    let w1 = try_open(&vfs).unwrap();
+    let w2 = try_open(&vfs).unwrap();
+
+    // Nothing changed since the read began
+    let tx = w1.begin_read().unwrap();
+    tx.begin_write().unwrap().commit().unwrap();
+
+    // Another connection commits in between
+    let tx = w1.begin_read().unwrap();
+    let mut other = w2.begin_write().unwrap();
+    other.write_page(1, page(0)).unwrap();
+    other.commit().unwrap();
+    match tx.begin_write() {
+        Err(Error(ErrorKind::BusySnapshot, _)) => { }
+        r => panic!("expected BusySnapshot, got {:?}", r.err()),
+    }
+
+    // Starting over sees the commit
+    let mut tx = w1.begin_read().unwrap().begin_write().unwrap();
+    assert_eq!(tx.db_size(), 2);
+    tx.write_page(1, page(0)).unwrap();
+    tx.commit().unwrap();
+}
@@ -0,0 +1,216 @@
+//! Allocates and frees pages, checking that freed pages are reused
+//! before the database grows and that the freelist is transactional
+
+extern crate btrs;
+
+mod common;
+
+use btrs::errors::*;
+use btrs::format::Trunk;
+use btrs::mem_vfs::MemVfs;
+use btrs::page::Page;
+use btrs::units::PageSize;
+use btrs::wabl::*;
+use btrs::wal::{CheckpointMode, PageNum};
+use common::*;
+use std::collections::BTreeSet;
+
+fn allocate(w: &Wabl, n: usize) -> Vec<PageNum> {
+    let mut tx = w.begin_read().unwrap().begin_write().unwrap();
+    let pages = (0..n).map(|_| tx.allocate_page().unwrap()).collect();
+    tx.commit().unwrap();
+    pages
+}
+
+fn free(w: &Wabl, pages: &[PageNum]) {
+    let mut tx = w.begin_read().unwrap().begin_write().unwrap();
+    for &n in pages {
+        tx.free_page(n).unwrap();
+    }
+    tx.commit().unwrap();
+}
+
+fn free_count(w: &Wabl) -> u32 {
+    w.begin_read().unwrap().begin_write().unwrap().free_count().unwrap()
+}
+
+#[test]
+fn grows_when_nothing_is_free() {
+    let vfs = MemVfs::new();
+    let w = open(&vfs);
+    assert_eq!(allocate(&w, 3), vec![1, 2, 3]);
+    assert_eq!(w.begin_read().unwrap().db_size().unwrap(), 4);
+    assert_eq!(free_count(&w), 0);
+}
+
+#[test]
+fn reuses_freed_pages() {
+    let vfs = MemVfs::new();
+    let w = open(&vfs);
+    allocate(&w, 5);
+    free(&w, &[2, 4]);
+    assert_eq!(free_count(&w), 2);
+
+    let reused = allocate(&w, 2).into_iter().collect::<BTreeSet<_>>();
+    assert_eq!(reused, vec![2, 4].into_iter().collect());
+    assert_eq!(free_count(&w), 0);
+    assert_eq!(allocate(&w, 1), vec![6]);
+}
+
+#[test]
+fn allocated_pages_are_zeroed() {
+    let vfs = MemVfs::new();
+    let w = open(&vfs);
+    allocate(&w, 4);
+    // Page 3 becomes a trunk, listing page 2
+    free(&w, &[3, 2]);
+
+    let mut tx = w.begin_read().unwrap().begin_write().unwrap();
+    for _ in 0..2 {
+        let n = tx.allocate_page().unwrap();
+        assert!(tx.read_page(n).unwrap().buf().iter().all(|&b| b == 0));
+    }
+}
+
+#[test]
+fn many_trunks() {
+    let vfs = MemVfs::new();
+    let w = open(&vfs);
+    let pages = allocate(&w, 3000);
+    free(&w, &pages);
+    assert_eq!(free_count(&w), 3000);
+
+    let reused = allocate(&w, 3000);
+    assert_eq!(reused.iter().cloned().collect::<BTreeSet<_>>(),
+               pages.iter().cloned().collect::<BTreeSet<_>>());
+    assert_eq!(allocate(&w, 1), vec![3001]);
+}
+
+#[test]
+fn rollback_restores_freelist() {
+    let vfs = MemVfs::new();
+    let w = open(&vfs);
+    allocate(&w, 3);
+    free(&w, &[1]);
+
+    let mut tx = w.begin_read().unwrap().begin_write().unwrap();
+    tx.free_page(2).unwrap();
+    assert_eq!(tx.allocate_page().unwrap(), 2);
+    assert_eq!(tx.allocate_page().unwrap(), 1);
+    tx.rollback().unwrap();
+
+    assert_eq!(free_count(&w), 1);
+    assert_eq!(allocate(&w, 2), vec![1, 4]);
+}
+
+#[test]
+fn survives_checkpoint_and_reopening() {
+    let vfs = MemVfs::new();
+    let w = open(&vfs);
+    allocate(&w, 4);
+    free(&w, &[1, 3]);
+    w.checkpoint(CheckpointMode::Truncate).unwrap();
+    drop(w);
+
+    let w = open(&vfs);
+    assert_eq!(w.check_integrity().unwrap(), vec![]);
+    assert_eq!(free_count(&w), 2);
+    let reused = allocate(&w, 2).into_iter().collect::<BTreeSet<_>>();
+    assert_eq!(reused, vec![1, 3].into_iter().collect());
+}
+
+#[test]
+fn bad_frees() {
+    let vfs = MemVfs::new();
+    let w = open(&vfs);
+    allocate(&w, 2);
+
+    let mut tx = w.begin_read().unwrap().begin_write().unwrap();
+    assert!(tx.free_page(0).is_err());
+    match tx.free_page(3) {
+        Err(Error(ErrorKind::PageOutOfRange(3, 3), _)) => { }
+        r => panic!("expected PageOutOfRange, got {:?}", r.err()),
+    }
+}
+
+#[test]
+fn shrinking_keeps_free_pages() {
+    let vfs = MemVfs::new();
+    let w = open(&vfs);
+    allocate(&w, 6);
+    // Page 2 becomes a trunk, listing page 4
+    free(&w, &[2, 4]);
+
+    let mut tx = w.begin_read().unwrap().begin_write().unwrap();
+    match tx.set_db_size(4) {
+        Err(Error(ErrorKind::FreePage(4), _)) => { }
+        r => panic!("expected FreePage, got {:?}", r.err()),
+    }
+    match tx.set_db_size(2) {
+        Err(Error(ErrorKind::FreePage(2), _)) => { }
+        r => panic!("expected FreePage, got {:?}", r.err()),
+    }
+    assert_eq!(tx.db_size(), 7);
+
+    // Pages past the freelist can go
+    tx.set_db_size(5).unwrap();
+    tx.commit().unwrap();
+    assert_eq!(w.check_integrity().unwrap(), vec![]);
+    assert_eq!(free_count(&w), 2);
+}
+
+/// Overwrites page `n`, which must be on the freelist, with a trunk
+fn write_trunk(w: &Wabl, n: PageNum, trunk: Trunk) {
+    let mut tx = w.begin_read().unwrap().begin_write().unwrap();
+    let mut page = Page::new(PageSize::new(PAGE_SIZE));
+    let bytes = trunk.to_bytes();
+    page.buf_mut()[..bytes.len()].copy_from_slice(&bytes);
+    tx.write_page(n, page).unwrap();
+    tx.commit().unwrap();
+}
+
+fn assert_corrupt_at(r: Result<PageNum>, page: PageNum, offset: u64) {
+    match r {
+        Err(Error(ErrorKind::Corrupt(ref c), _))
+            if c.page == Some(page) && c.offset == page as u64 * PAGE_SIZE as u64 + offset => { }
+        r => panic!("expected corruption at {} in page {}, got {:?}", offset, page, r),
+    }
+}
+
+#[test]
+fn damaged_freelist() {
+    let vfs = MemVfs::new();
+    let w = open(&vfs);
+    allocate(&w, 4);
+    // Page 3 becomes a trunk, listing page 2
+    free(&w, &[3, 2]);
+
+    // A leaf past the end of the database
+    write_trunk(&w, 3, Trunk { next: 0, leaves: vec![9] });
+    let problems = w.check_integrity().unwrap();
+    assert_eq!(problems.len(), 1);
+    assert_eq!((problems[0].page, problems[0].offset), (Some(3), 3 * PAGE_SIZE as u64 + 8));
+    let mut tx = w.begin_read().unwrap().begin_write().unwrap();
+    assert_corrupt_at(tx.allocate_page(), 3, 8);
+    drop(tx);
+
+    // A trunk that lists itself as the next trunk
+    write_trunk(&w, 3, Trunk { next: 3, leaves: vec![2] });
+    let problems = w.check_integrity().unwrap();
+    assert_eq!(problems.len(), 1);
+    assert_eq!((problems[0].page, problems[0].offset), (Some(3), 3 * PAGE_SIZE as u64));
+    let mut tx = w.begin_read().unwrap().begin_write().unwrap();
+    assert_eq!(tx.allocate_page().unwrap(), 2);
+    assert_corrupt_at(tx.allocate_page(), 3, 0);
+    drop(tx);
+
+    // More pages than the count says
+    write_trunk(&w, 3, Trunk { next: 0, leaves: vec![2, 1] });
+    let problems = w.check_integrity().unwrap();
+    assert_eq!(problems.len(), 1);
+    assert_eq!((problems[0].page, problems[0].offset), (Some(0), 24));
+    let mut tx = w.begin_read().unwrap().begin_write().unwrap();
+    assert_eq!(tx.allocate_page().unwrap(), 1);
+    assert_eq!(tx.allocate_page().unwrap(), 2);
+    assert_corrupt_at(tx.allocate_page(), 0, 24);
+}
@@ -0,0 +1,93 @@
+//! Commits sharing syncs of the log
+
+extern crate btrs;
+
+use btrs::fault_vfs::{FaultVfs, Op};
+use btrs::page::Page;
+use btrs::units::PageSize;
+use btrs::vfs::Vfs;
+use btrs::wal::{CheckpointMode, Synchronous, Wal};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+const PAGE_SIZE: u32 = 512;
+const PATH: &str = "group";
+const WINDOW_MS: u64 = 300;
+
+fn open(vfs: &FaultVfs, synchronous: Synchronous) -> Wal {
+    let mut wal = Wal::new(Arc::new(vfs.clone()), PATH, PageSize::new(PAGE_SIZE)).unwrap();
+    wal.set_synchronous(synchronous);
+    wal.set_group_commit(Some(Duration::from_millis(WINDOW_MS)));
+    wal
+}
+
+fn write(wal: &Wal, n: u32) {
+    let mut tx = wal.begin_write().unwrap();
+    tx.set_db_size(1);
+    let mut page = Page::new(PageSize::new(PAGE_SIZE));
+    page.buf_mut()[0] = n as u8;
+    tx.write_page(n, page).unwrap();
+    tx.commit().unwrap();
+}
+
+#[test]
+fn committers_share_a_sync() {
+    let vfs = FaultVfs::new(0);
+    let wal = Arc::new(open(&vfs, Synchronous::Full));
+    let syncs = vfs.op_count(Op::Sync);
+
+    // The second commit lands in the first one's window, so the
+    // first committer's sync covers both
+    let threads = (1..3).map(|n| {
+        let wal = wal.clone();
+        thread::sleep(Duration::from_millis(WINDOW_MS / 6));
+        thread::spawn(move || write(&wal, n))
+    }).collect::<Vec<_>>();
+    for t in threads {
+        t.join().unwrap();
+    }
+    assert_eq!(vfs.op_count(Op::Sync) - syncs, 1);
+
+    // Once the window has passed the next commit syncs again
+    write(&wal, 3);
+    assert_eq!(vfs.op_count(Op::Sync) - syncs, 2);
+}
+
+#[test]
+fn restart_without_sync() {
+    let vfs = FaultVfs::new(0);
+    let wal = Arc::new(open(&vfs, Synchronous::Full));
+    let unsynced = open(&vfs, Synchronous::Off);
+    write(&wal, 1);
+    let syncs = vfs.op_count(Op::Sync);
+
+    // While the committer waits for others to join in, a connection
+    // that doesn't sync checkpoints its frames into the database and
+    // restarts the log. Its epoch has moved on, but nothing reached
+    // the disk.
+    let committer = {
+        let wal = wal.clone();
+        thread::spawn(move || write(&wal, 2))
+    };
+    thread::sleep(Duration::from_millis(WINDOW_MS / 3));
+    let db_path = Path::new(PATH).with_extension("db");
+    {
+        let checkpoint = unsynced.begin_checkpoint(CheckpointMode::Restart).unwrap();
+        let db = vfs.open(&db_path).unwrap();
+        for &n in checkpoint.pages() {
+            let page = checkpoint.read_page(n).unwrap().unwrap();
+            db.write_at(page.buf(), n as u64 * PAGE_SIZE as u64).unwrap();
+        }
+        checkpoint.finish().unwrap();
+    }
+    assert_eq!(vfs.op_count(Op::Sync), syncs);
+    assert!(!vfs.is_synced(&db_path));
+
+    // So the committer syncs the database instead
+    committer.join().unwrap();
+    assert_eq!(vfs.op_count(Op::Sync) - syncs, 1);
+    assert!(vfs.is_synced(&db_path));
+    assert!(!vfs.exists(Path::new(PATH)));
+}
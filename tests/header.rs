@@ -0,0 +1,130 @@
+//! Checks the database header in page 0: what it records, that it is
+//! validated on open, and that pages can't be read or written over it
+
+extern crate btrs;
+
+mod common;
+
+use btrs::errors::*;
+use btrs::format::DB_VERSION;
+use btrs::mem_vfs::MemVfs;
+use btrs::vfs::Vfs;
+use btrs::wal::CheckpointMode;
+use common::*;
+
+fn write_db(vfs: &MemVfs, data: &[u8], offset: u64) {
+    let file = vfs.open(&path("db")).unwrap();
+    file.write_at(data, offset).unwrap();
+}
+
+#[test]
+fn new_database() {
+    let vfs = MemVfs::new();
+    let w = try_open(&vfs).unwrap();
+    let mut tx = w.begin_read().unwrap();
+    let h = tx.header().unwrap();
+    assert_eq!((h.version, h.page_size.to_u32(), h.page_count), (DB_VERSION, PAGE_SIZE, 1));
+    assert_eq!((h.freelist_trunk, h.freelist_count, h.change_counter), (0, 0, 0));
+    assert_eq!((h.schema_root, h.application_id, h.user_version), (0, 0, 0));
+    assert_eq!(tx.db_size().unwrap(), 1);
+}
+
+#[test]
+fn page_0_is_reserved() {
+    let vfs = MemVfs::new();
+    let w = try_open(&vfs).unwrap();
+    let reserved = |r: Result<()>| match r {
+        Err(Error(ErrorKind::ReservedPage(0), _)) => { }
+        r => panic!("expected ReservedPage, got {:?}", r.err()),
+    };
+    reserved(w.begin_read().unwrap().read_page(0).map(|_| ()));
+    let mut tx = w.begin_read().unwrap().begin_write().unwrap();
+    reserved(tx.read_page(0).map(|_| ()));
+    reserved(tx.write_page(0, page(0)));
+    reserved(tx.set_db_size(0));
+    reserved(tx.free_page(0));
+    assert_eq!(tx.allocate_page().unwrap(), 1);
+}
+
+#[test]
+fn commits_update_the_header() {
+    let vfs = MemVfs::new();
+    let w = try_open(&vfs).unwrap();
+    for n in 1..4 {
+        let mut tx = w.begin_read().unwrap().begin_write().unwrap();
+        tx.write_page(n * 2, page(0)).unwrap();
+        tx.commit().unwrap();
+        let h = w.begin_read().unwrap().header().unwrap();
+        assert_eq!((h.change_counter, h.page_count), (n, n * 2 + 1));
+    }
+
+    // Shrinking alone is a change too
+    let mut tx = w.begin_read().unwrap().begin_write().unwrap();
+    tx.set_db_size(2).unwrap();
+    tx.commit().unwrap();
+    let h = w.begin_read().unwrap().header().unwrap();
+    assert_eq!((h.change_counter, h.page_count), (4, 2));
+
+    // And a transaction that writes nothing isn't
+    w.begin_read().unwrap().begin_write().unwrap().commit().unwrap();
+    assert_eq!(w.begin_read().unwrap().header().unwrap().change_counter, 4);
+}
+
+#[test]
+fn application_fields_persist() {
+    let vfs = MemVfs::new();
+    let w = try_open(&vfs).unwrap();
+    let mut tx = w.begin_read().unwrap().begin_write().unwrap();
+    let root = tx.allocate_page().unwrap();
+    tx.set_schema_root(root).unwrap();
+    tx.set_application_id(0x62747273).unwrap();
+    tx.set_user_version(7).unwrap();
+    tx.commit().unwrap();
+    w.checkpoint(CheckpointMode::Truncate).unwrap();
+    drop(w);
+
+    let w = try_open(&vfs).unwrap();
+    let h = w.begin_read().unwrap().header().unwrap();
+    assert_eq!((h.schema_root, h.application_id, h.user_version), (root, 0x62747273, 7));
+    assert_eq!(w.check_integrity().unwrap(), vec![]);
+}
+
+#[test]
+fn damaged_header() {
+    let vfs = MemVfs::new();
+    drop(try_open(&vfs).unwrap());
+    // The user version
+    write_db(&vfs, &[1], 40);
+    match try_open(&vfs) {
+        Err(Error(ErrorKind::Corrupt(ref c), _)) => {
+            assert_eq!((c.offset, c.page), (44, Some(0)));
+        }
+        r => panic!("expected Corrupt, got {:?}", r.err()),
+    }
+}
+
+#[test]
+fn page_count_mismatch() {
+    let vfs = MemVfs::new();
+    drop(try_open(&vfs).unwrap());
+    // A page the header doesn't count
+    let file = vfs.open(&path("db")).unwrap();
+    file.set_len(2 * PAGE_SIZE as u64).unwrap();
+    match try_open(&vfs) {
+        Err(Error(ErrorKind::Corrupt(ref c), _)) => {
+            assert_eq!((c.offset, c.page), (16, Some(0)));
+        }
+        r => panic!("expected Corrupt, got {:?}", r.err()),
+    }
+}
+
+#[test]
+fn unsupported_version() {
+    let vfs = MemVfs::new();
+    drop(try_open(&vfs).unwrap());
+    write_db(&vfs, &[DB_VERSION as u8 + 1], 12);
+    match try_open(&vfs) {
+        Err(Error(ErrorKind::UnsupportedVersion(v), _)) => assert_eq!(v, DB_VERSION + 1),
+        r => panic!("expected UnsupportedVersion, got {:?}", r.err()),
+    }
+}
@@ -0,0 +1,163 @@
+//! Damages each of the database's files and checks that the damage
+//! is reported where it is, both by reads and by `check_integrity`
+
+extern crate btrs;
+
+mod common;
+
+use btrs::errors::*;
+use btrs::integrity::Corruption;
+use btrs::mem_vfs::MemVfs;
+use btrs::vfs::Vfs;
+use btrs::wabl::*;
+use btrs::wal::CheckpointMode;
+use btrs::wal_index::REGION_SIZE;
+use common::{path, PAGE_SIZE};
+use std::ptr;
+
+/// The space before the first frame of the log
+const WAL_HEADER_SIZE: u64 = 100;
+const FRAME_HEADER_SIZE: u64 = 32;
+
+fn frame_offset(fr: u32) -> u64 {
+    WAL_HEADER_SIZE + fr as u64 * (FRAME_HEADER_SIZE + PAGE_SIZE as u64)
+}
+
+/// The frame of the log holding page `n` in `database`
+fn frame_of(n: u32) -> u32 {
+    // Each commit logs page 0, holding the header, then the page
+    2 * n - 1
+}
+
+/// Creates a database with pages 1 to 3 in the log, one per commit
+fn database(vfs: &MemVfs) -> Wabl {
+    let w = common::open(vfs);
+    for n in 1..4 {
+        common::write(&w, n, n as u8);
+    }
+    w
+}
+
+fn write(vfs: &MemVfs, ext: &str, data: &[u8], offset: u64) {
+    let file = vfs.open(&path(ext)).unwrap();
+    file.write_at(data, offset).unwrap();
+}
+
+fn write_shm(vfs: &MemVfs, data: &[u8], offset: usize) {
+    let shm = vfs.open_shm(&path("shm")).unwrap();
+    let region = shm.region((offset / REGION_SIZE) as u32, REGION_SIZE).unwrap();
+    unsafe {
+        let dst = region.add(offset % REGION_SIZE);
+        ptr::copy_nonoverlapping(data.as_ptr(), dst, data.len());
+    }
+}
+
+fn assert_located(c: &Corruption, ext: &str, offset: u64,
+                  frame: Option<u32>, page: Option<u32>) {
+    assert_eq!((&c.file, c.offset, c.frame, c.page),
+               (&path(ext), offset, frame, page),
+               "wrong location in {}", c);
+}
+
+#[test]
+fn sound_database() {
+    let vfs = MemVfs::new();
+    let w = database(&vfs);
+    assert_eq!(w.check_integrity().unwrap(), vec![]);
+    w.checkpoint(CheckpointMode::Passive).unwrap();
+    assert_eq!(w.check_integrity().unwrap(), vec![]);
+    w.checkpoint(CheckpointMode::Truncate).unwrap();
+    assert_eq!(w.check_integrity().unwrap(), vec![]);
+}
+
+#[test]
+fn damaged_frame() {
+    let vfs = MemVfs::new();
+    let w = database(&vfs);
+    let fr = frame_of(2);
+    write(&vfs, "wal", &[0xff], frame_offset(fr) + FRAME_HEADER_SIZE + 100);
+
+    let problems = w.check_integrity().unwrap();
+    assert_eq!(problems.len(), 1, "{:?}", problems);
+    assert_located(&problems[0], "wal", frame_offset(fr), Some(fr), Some(2));
+    assert!(problems[0].expected.starts_with("checksum"), "{}", problems[0]);
+}
+
+#[test]
+fn frame_holding_the_wrong_page() {
+    let vfs = MemVfs::new();
+    let w = database(&vfs);
+    // The page number at the start of the frame header
+    let fr = frame_of(2);
+    write(&vfs, "wal", &[5, 0, 0, 0], frame_offset(fr));
+
+    let mut tx = w.begin_read().unwrap();
+    match tx.read_page(2) {
+        Err(Error(ErrorKind::Corrupt(ref c), _)) => {
+            assert_located(c, "wal", frame_offset(fr), Some(fr), Some(2));
+        }
+        r => panic!("expected Corrupt, got {:?}", r.err()),
+    }
+    drop(tx);
+
+    let problems = w.check_integrity().unwrap();
+    assert_eq!(problems.len(), 1, "{:?}", problems);
+    assert_located(&problems[0], "wal", frame_offset(fr), Some(fr), Some(5));
+}
+
+#[test]
+fn damaged_wal_header() {
+    let vfs = MemVfs::new();
+    let w = database(&vfs);
+    // The epoch, covered by the header checksum after it
+    write(&vfs, "wal", &[0xff], 12);
+
+    let problems = w.check_integrity().unwrap();
+    assert_eq!(problems.len(), 1, "{:?}", problems);
+    assert_located(&problems[0], "wal", 28, None, None);
+    assert!(problems[0].expected.starts_with("header checksum"), "{}", problems[0]);
+}
+
+#[test]
+fn damaged_index() {
+    let vfs = MemVfs::new();
+    let w = database(&vfs);
+    // The page number the index records for the frame
+    let fr = frame_of(2);
+    let offset = REGION_SIZE + fr as usize * 4;
+    write_shm(&vfs, &[7, 0, 0, 0], offset);
+
+    let problems = w.check_integrity().unwrap();
+    assert_eq!(problems.len(), 1, "{:?}", problems);
+    assert_located(&problems[0], "shm", offset as u64, Some(fr), Some(2));
+    assert_eq!((&problems[0].expected[..], &problems[0].found[..]), ("page 2", "page 7"));
+}
+
+#[test]
+fn partial_page() {
+    let vfs = MemVfs::new();
+    let w = database(&vfs);
+    w.checkpoint(CheckpointMode::Passive).unwrap();
+    let db = vfs.open(&path("db")).unwrap();
+    db.set_len(4 * PAGE_SIZE as u64 + 10).unwrap();
+
+    let problems = w.check_integrity().unwrap();
+    assert_eq!(problems.len(), 1, "{:?}", problems);
+    assert_located(&problems[0], "db", 4 * PAGE_SIZE as u64, None, Some(4));
+}
+
+#[test]
+fn every_problem_listed() {
+    let vfs = MemVfs::new();
+    let w = database(&vfs);
+    let (fr1, fr3) = (frame_of(1), frame_of(3));
+    write(&vfs, "wal", &[0xff], frame_offset(fr1) + FRAME_HEADER_SIZE);
+    write(&vfs, "wal", &[0xff], frame_offset(fr3) + FRAME_HEADER_SIZE);
+    write(&vfs, "db", &[0; 8], 0);
+
+    let problems = w.check_integrity().unwrap();
+    assert_eq!(problems.len(), 3, "{:?}", problems);
+    assert_located(&problems[0], "wal", frame_offset(fr1), Some(fr1), Some(1));
+    assert_located(&problems[1], "wal", frame_offset(fr3), Some(fr3), Some(3));
+    assert_located(&problems[2], "db", 0, None, Some(0));
+}
@@ -0,0 +1,48 @@
+//! A database kept entirely in memory, shared by several connections
+
+extern crate btrs;
+
+mod common;
+
+use btrs::mem_vfs::MemVfs;
+use btrs::wal::CheckpointMode;
+use common::*;
+
+#[test]
+fn shared_database() {
+    let vfs = MemVfs::new();
+    let w1 = open(&vfs);
+    let w2 = open(&vfs);
+
+    // Commits through one handle are visible through the other
+    write(&w1, 1, 1);
+    write(&w2, 2, 2);
+    assert_eq!((read(&w2, 1), read(&w1, 2)), (1, 2));
+    assert_eq!(file_len(&vfs, "db"), PAGE_SIZE as u64);
+
+    // A reader keeps its snapshot while the other handle commits
+    let mut tx = w1.begin_read().unwrap();
+    write(&w2, 1, 3);
+    assert_eq!(tx.read_page(1).unwrap().buf()[0], 1);
+    drop(tx);
+    assert_eq!(read(&w1, 1), 3);
+
+    // Checkpointing through one handle moves the pages into the
+    // database file for both
+    w2.checkpoint(CheckpointMode::Truncate).unwrap();
+    assert_eq!(file_len(&vfs, "db"), 3 * PAGE_SIZE as u64);
+    assert_eq!(file_len(&vfs, "wal"), 0);
+    assert_eq!((read(&w1, 1), read(&w1, 2)), (3, 2));
+
+    // The log starts over and later handles see everything
+    write(&w1, 2, 4);
+    let w3 = open(&vfs);
+    assert_eq!((read(&w3, 1), read(&w3, 2)), (3, 4));
+    drop((w1, w2));
+    w3.checkpoint(CheckpointMode::Passive).unwrap();
+    assert!(w3.check_integrity().unwrap().is_empty());
+
+    // Each `MemVfs` is its own filesystem
+    let other = open(&MemVfs::new());
+    assert_eq!(other.begin_read().unwrap().db_size().unwrap(), 1);
+}
@@ -0,0 +1,323 @@
+//! Drives `Wabl` with random sequences of operations on two
+//! connections and compares every page read against a simple model.
+//! A failing sequence is shrunk to a minimal one before reporting it.
+
+extern crate btrs;
+extern crate rand;
+
+mod common;
+
+use btrs::errors::*;
+use btrs::lock::BusyHandler;
+use btrs::mem_vfs::MemVfs;
+use btrs::page::Page;
+use btrs::units::PageSize;
+use btrs::wabl::*;
+use btrs::wal::{CheckpointMode, PageNum};
+use common::{try_open, PAGE_SIZE};
+use rand::{Rng, SeedableRng, XorShiftRng};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+const CONNECTIONS: usize = 2;
+/// Pages are drawn from `1..MAX_PAGE`. Page 0 holds the database
+/// header.
+const MAX_PAGE: PageNum = 12;
+const SEQUENCES: u32 = 200;
+const SEQUENCE_LEN: usize = 100;
+
+#[derive(Clone, Debug)]
+enum Op {
+    /// Starts a read transaction, ending any already open
+    BeginRead(usize),
+    /// Reads in the open read transaction, if any
+    ReadPage(usize, PageNum),
+    EndRead(usize),
+    /// Writes in a new transaction, or by upgrading the open read
+    /// transaction
+    Write {
+        conn: usize,
+        /// Set before writing the pages
+        size: Option<PageNum>,
+        /// Pages and the byte to fill them with
+        pages: Vec<(PageNum, u8)>,
+        commit: bool,
+    },
+    Checkpoint(usize, CheckpointMode),
+}
+
+/// The database as of some commit. Pages whose contents aren't
+/// defined, e.g. after the database shrank and grew again, are left
+/// out.
+#[derive(Clone)]
+struct Model {
+    size: PageNum,
+    pages: HashMap<PageNum, Page>,
+}
+
+fn page(n: PageNum, fill: u8) -> Page {
+    let mut page = Page::new(PageSize::new(PAGE_SIZE));
+    for x in page.buf_mut() {
+        *x = fill;
+    }
+    // Tell pages apart even when they have the same fill
+    page.buf_mut()[0] = n as u8;
+    page
+}
+
+impl Model {
+    /// A new database, which has only page 0
+    fn new() -> Model {
+        Model {
+            size: 1,
+            pages: HashMap::new(),
+        }
+    }
+
+    fn set_size(&mut self, n: PageNum) {
+        if n > self.size {
+            // The new last page is zeroed, the ones before it are
+            // undefined
+            self.pages.insert(n - 1, Page::new(PageSize::new(PAGE_SIZE)));
+        } else {
+            self.pages.retain(|&p, _| p < n);
+        }
+        self.size = n;
+    }
+
+    fn write(&mut self, n: PageNum, fill: u8) {
+        self.pages.insert(n, page(n, fill));
+        self.size = self.size.max(n + 1);
+    }
+}
+
+fn gen_op(rng: &mut XorShiftRng) -> Op {
+    let conn = rng.gen_range(0, CONNECTIONS);
+    match rng.gen_range(0, 10) {
+        0 | 1 => Op::BeginRead(conn),
+        2..=4 => Op::ReadPage(conn, rng.gen_range(1, MAX_PAGE)),
+        5 => Op::EndRead(conn),
+        6..=8 => {
+            let size = if rng.gen_range(0, 5) == 0 {
+                Some(rng.gen_range(1, MAX_PAGE))
+            } else {
+                None
+            };
+            let pages = (0..rng.gen_range(0, 5))
+                .map(|_| (rng.gen_range(1, MAX_PAGE), rng.gen()))
+                .collect();
+            Op::Write {
+                conn: conn,
+                size: size,
+                pages: pages,
+                commit: rng.gen_range(0, 5) != 0,
+            }
+        }
+        _ => {
+            let modes = [CheckpointMode::Passive, CheckpointMode::Full,
+                         CheckpointMode::Restart, CheckpointMode::Truncate];
+            Op::Checkpoint(conn, *rng.choose(&modes).unwrap())
+        }
+    }
+}
+
+fn open(vfs: &MemVfs) -> Result<Wabl> {
+    let mut w = try_open(vfs)?;
+    // Everything runs on one thread, so waiting would never end
+    w.set_busy_handler(BusyHandler::Callback(Arc::new(|_| false)));
+    w.set_auto_checkpoint(AutoCheckpoint::Frames(5));
+    Ok(w)
+}
+
+fn is_busy(e: &Error) -> bool {
+    matches!(*e.kind(), ErrorKind::Busy | ErrorKind::BusySnapshot)
+}
+
+/// Runs `ops` against a new database, returning a description of the
+/// first discrepancy with the model
+fn run(ops: &[Op]) -> std::result::Result<(), String> {
+    let vfs = MemVfs::new();
+    let wabls = (0..CONNECTIONS).map(|_| open(&vfs))
+        .collect::<Result<Vec<_>>>().map_err(|e| e.to_string())?;
+    // Each open read transaction, the model as of its snapshot, and
+    // the number of changes to the log when it started
+    let mut reads = (0..CONNECTIONS).map(|_| None).collect::<Vec<_>>();
+    let mut model = Model::new();
+    // Counts anything that may change the log, so that a snapshot is
+    // only allowed to be out of date if something happened
+    let mut changes = 0;
+
+    for (i, op) in ops.iter().enumerate() {
+        let fail = |msg: String| Err(format!("op {} ({:?}): {}", i, op, msg));
+        match *op {
+            Op::BeginRead(c) => {
+                reads[c] = None;
+                match wabls[c].begin_read() {
+                    Ok(tx) => reads[c] = Some((tx, model.clone(), changes)),
+                    Err(e) => return fail(e.to_string()),
+                }
+            }
+            Op::ReadPage(c, n) => {
+                if let Some((ref mut tx, ref snapshot, _)) = reads[c] {
+                    match (tx.read_page(n), n < snapshot.size) {
+                        (Ok(ref p), true) => {
+                            if let Some(expected) = snapshot.pages.get(&n) {
+                                if p.buf() != expected.buf() {
+                                    return fail("wrong page contents".to_string());
+                                }
+                            }
+                        }
+                        (Err(Error(ErrorKind::PageOutOfRange(..), _)), false) => { }
+                        (Ok(_), false) => return fail("read past the end".to_string()),
+                        (Err(e), _) => return fail(e.to_string()),
+                    }
+                }
+            }
+            Op::EndRead(c) => {
+                reads[c] = None;
+            }
+            Op::Write { conn: c, size, ref pages, commit } => {
+                changes += 1;
+                let upgrade = reads[c].take();
+                let tx = match upgrade {
+                    Some((tx, _, since)) => {
+                        match tx.begin_write() {
+                            Ok(tx) => tx,
+                            Err(ref e) if is_busy(e) && since != changes - 1 => continue,
+                            Err(e) => return fail(e.to_string()),
+                        }
+                    }
+                    None => {
+                        match wabls[c].begin_read().and_then(|tx| tx.begin_write()) {
+                            Ok(tx) => tx,
+                            Err(e) => return fail(e.to_string()),
+                        }
+                    }
+                };
+
+                let mut tx = tx;
+                let mut after = model.clone();
+                if let Some(n) = size {
+                    if let Err(e) = tx.set_db_size(n) {
+                        return fail(e.to_string());
+                    }
+                    after.set_size(n);
+                }
+                for &(n, fill) in pages {
+                    if let Err(e) = tx.write_page(n, page(n, fill)) {
+                        return fail(e.to_string());
+                    }
+                    after.write(n, fill);
+                }
+
+                if commit {
+                    if let Err(e) = tx.commit() {
+                        return fail(e.to_string());
+                    }
+                    model = after;
+                } else if let Err(e) = tx.rollback() {
+                    return fail(e.to_string());
+                }
+            }
+            Op::Checkpoint(c, mode) => {
+                changes += 1;
+                match wabls[c].checkpoint(mode) {
+                    Ok(_) => { }
+                    Err(ref e) if is_busy(e) => { }
+                    Err(e) => return fail(e.to_string()),
+                }
+            }
+        }
+    }
+
+    // Everything committed must also be visible to a new connection
+    drop(reads);
+    let w = open(&vfs).map_err(|e| e.to_string())?;
+    let mut tx = w.begin_read().map_err(|e| e.to_string())?;
+    if tx.db_size().map_err(|e| e.to_string())? != model.size {
+        return Err("wrong size after reopening".to_string());
+    }
+    for (&n, expected) in &model.pages {
+        let p = tx.read_page(n).map_err(|e| e.to_string())?;
+        if p.buf() != expected.buf() {
+            return Err(format!("wrong contents of page {} after reopening", n));
+        }
+    }
+
+    Ok(())
+}
+
+/// Simpler versions of `op` to try while shrinking
+fn simplify(op: &Op) -> Vec<Op> {
+    match *op {
+        Op::Write { conn, size, ref pages, commit } => {
+            let mut simpler = Vec::new();
+            if size.is_some() {
+                simpler.push(Op::Write { conn: conn, size: None, pages: pages.clone(), commit: commit });
+            }
+            for i in 0..pages.len() {
+                let mut pages = pages.clone();
+                pages.remove(i);
+                simpler.push(Op::Write { conn: conn, size: size, pages: pages, commit: commit });
+            }
+            simpler
+        }
+        Op::Checkpoint(conn, mode) if mode != CheckpointMode::Passive => {
+            vec![Op::Checkpoint(conn, CheckpointMode::Passive)]
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Shrinks a failing sequence until no smaller one fails: first by
+/// removing runs of operations, halving their length, then by
+/// simplifying single operations
+fn shrink(mut ops: Vec<Op>) -> Vec<Op> {
+    let mut progress = true;
+    while progress {
+        progress = false;
+
+        let mut chunk = ops.len() / 2;
+        while chunk > 0 {
+            let mut start = 0;
+            while start + chunk <= ops.len() {
+                let mut candidate = ops.clone();
+                candidate.drain(start..start + chunk);
+                if run(&candidate).is_err() {
+                    ops = candidate;
+                    progress = true;
+                } else {
+                    start += chunk;
+                }
+            }
+            chunk /= 2;
+        }
+
+        for i in 0..ops.len() {
+            for op in simplify(&ops[i]) {
+                let mut candidate = ops.clone();
+                candidate[i] = op;
+                if run(&candidate).is_err() {
+                    ops = candidate;
+                    progress = true;
+                    break;
+                }
+            }
+        }
+    }
+
+    ops
+}
+
+#[test]
+fn random_operations_match_model() {
+    for seed in 0..SEQUENCES {
+        let mut rng = XorShiftRng::from_seed([seed + 1, 0x5f3759df, 0x6c8e9cf5, 0x1b873593]);
+        let ops = (0..SEQUENCE_LEN).map(|_| gen_op(&mut rng)).collect::<Vec<_>>();
+        if let Err(e) = run(&ops) {
+            let ops = shrink(ops);
+            panic!("seed {}: {}\nminimal failing sequence:\n{:#?}\nwhich fails with: {}",
+                   seed, e, ops, run(&ops).unwrap_err());
+        }
+    }
+}
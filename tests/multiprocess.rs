@@ -0,0 +1,286 @@
+//! Runs readers, writers and checkpointers in separate processes
+//! against one database.
+//!
+//! Each child re-runs this test binary, selecting the `child` test
+//! and passing its role in the environment. Every transaction sets
+//! the same counter in each of `PAGES` pages. Readers check that
+//! their snapshots are consistent and that the counter never goes
+//! backwards, and at the end the counter must equal the number of
+//! commits, so none were lost or applied twice.
+//!
+//! Each test runs several times, with the same seeds every time, and
+//! prints the seed of a run that fails. Setting `BTRS_TEST_RUN_SEED`
+//! repeats just that run. Each run interleaves a few hundred
+//! transactions across the processes; to cover thousands of
+//! interleavings, raise the number of runs with `BTRS_TEST_RUNS` and
+//! set `BTRS_TEST_RANDOM_SEEDS` to draw new seeds from the time, e.g.
+//!
+//! ```text
+//! BTRS_TEST_RUNS=1000 BTRS_TEST_RANDOM_SEEDS=1 cargo test --test multiprocess
+//! ```
+
+extern crate btrs;
+extern crate rand;
+
+use btrs::errors::*;
+use btrs::lock::BusyHandler;
+use btrs::page::Page;
+use btrs::units::PageSize;
+use btrs::vfs::OsVfs;
+use btrs::wabl::*;
+use btrs::wal::{CheckpointMode, PageNum, Synchronous};
+use rand::{Rng, SeedableRng, XorShiftRng};
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const PAGE_SIZE: u32 = 4096;
+/// The pages holding the counter, starting at page 1
+const PAGES: PageNum = 4;
+const ROLE: &str = "BTRS_TEST_ROLE";
+const DIR: &str = "BTRS_TEST_DIR";
+const SEED: &str = "BTRS_TEST_SEED";
+const COUNT: &str = "BTRS_TEST_COUNT";
+const RUN_SEED: &str = "BTRS_TEST_RUN_SEED";
+const RUNS: &str = "BTRS_TEST_RUNS";
+const RANDOM_SEEDS: &str = "BTRS_TEST_RANDOM_SEEDS";
+/// Enough to keep the suite quick
+const DEFAULT_RUNS: u32 = 3;
+
+fn open(dir: &Path) -> Result<Wabl> {
+    let mut w = Wabl::new(Arc::new(OsVfs), &dir.join("db"))?;
+    // Process death doesn't lose unsynced writes, only power loss
+    w.set_synchronous(Synchronous::Normal);
+    w.set_auto_checkpoint(AutoCheckpoint::Frames(50));
+    Ok(w)
+}
+
+fn counter_page(n: u64) -> Page {
+    let mut page = Page::new(PageSize::new(PAGE_SIZE));
+    for (i, x) in page.buf_mut()[..8].iter_mut().enumerate() {
+        *x = (n >> (i * 8)) as u8;
+    }
+    page
+}
+
+fn counter(page: &Page) -> u64 {
+    page.buf()[..8].iter().enumerate()
+        .fold(0, |n, (i, &x)| n | (x as u64) << (i * 8))
+}
+
+/// Creates the database with the counter at 0
+fn create(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("btrs-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let w = open(&dir).unwrap();
+    let mut tx = w.begin_read().unwrap().begin_write().unwrap();
+    for p in 1..PAGES + 1 {
+        tx.write_page(p, counter_page(0)).unwrap();
+    }
+    tx.commit().unwrap();
+    dir
+}
+
+fn final_counter(dir: &Path) -> u64 {
+    let w = open(dir).unwrap();
+    let mut tx = w.begin_read().unwrap();
+    counter(&tx.read_page(1).unwrap())
+}
+
+fn spawn(role: &str, dir: &Path, seed: u32, count: u32) -> Child {
+    Command::new(env::current_exe().unwrap())
+        .args(["child", "--exact", "--nocapture", "--test-threads", "1"])
+        .env(ROLE, role)
+        .env(DIR, dir)
+        .env(SEED, seed.to_string())
+        .env(COUNT, count.to_string())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap()
+}
+
+/// Waits for a child and returns how many commits it reported
+fn wait(child: Child) -> (bool, u64) {
+    let output = child.wait_with_output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    // The test harness's own output can share a line with ours
+    let commits = stdout.matches("commit\n").count() as u64;
+    (output.status.success(), commits)
+}
+
+/// Runs `test` once for each seed, naming the seed if it fails
+fn for_each_seed<F: Fn(u32)>(name: &str, test: F) {
+    let seeds = match env::var(RUN_SEED) {
+        Ok(s) => vec![s.parse().expect("seed")],
+        Err(_) => {
+            let runs = env::var(RUNS).map(|s| s.parse().expect("runs")).unwrap_or(DEFAULT_RUNS);
+            let base = if env::var_os(RANDOM_SEEDS).is_some() {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+                now.as_secs() as u32 ^ now.subsec_nanos()
+            } else {
+                1
+            };
+            (0..runs).map(|i| base.wrapping_add(i.wrapping_mul(0x9e3779b9))).collect()
+        }
+    };
+
+    for seed in seeds {
+        if let Err(e) = panic::catch_unwind(AssertUnwindSafe(|| test(seed))) {
+            println!("{} failed; rerun with {}={}", name, RUN_SEED, seed);
+            panic::resume_unwind(e);
+        }
+    }
+}
+
+/// The entry point of the child processes. Does nothing when run as
+/// part of the test suite.
+#[test]
+fn child() {
+    let role = match env::var(ROLE) {
+        Ok(r) => r,
+        Err(_) => return,
+    };
+    let dir = PathBuf::from(env::var(DIR).unwrap());
+    let seed = env::var(SEED).unwrap().parse().unwrap();
+    let count = env::var(COUNT).unwrap().parse().unwrap();
+    let mut rng = XorShiftRng::from_seed([seed, 0x2f6b0b6d, 0x5a1c3e85, 0x0c9d07e3]);
+    let w = open(&dir).unwrap();
+
+    match &role[..] {
+        "writer" => writer(&w, &mut rng, count),
+        "reader" => reader(&w, &mut rng, count),
+        "checkpointer" => checkpointer(w, &mut rng, count),
+        _ => panic!("unknown role {}", role),
+    }
+}
+
+fn pause(rng: &mut XorShiftRng) {
+    match rng.gen_range(0, 4) {
+        0 => thread::sleep(Duration::from_micros(rng.gen_range(0, 2000))),
+        1 => thread::yield_now(),
+        _ => { }
+    }
+}
+
+fn writer(w: &Wabl, rng: &mut XorShiftRng, count: u32) {
+    for _ in 0..count {
+        let mut tx = w.begin_write().unwrap();
+
+        let n = counter(&tx.read_page(1).unwrap());
+        for p in 2..PAGES + 1 {
+            assert_eq!(counter(&tx.read_page(p).unwrap()), n, "inconsistent snapshot");
+        }
+
+        // Write the pages in a random order, pausing in between
+        let mut pages = (1..PAGES + 1).collect::<Vec<_>>();
+        rng.shuffle(&mut pages);
+        for p in pages {
+            tx.write_page(p, counter_page(n + 1)).unwrap();
+            pause(rng);
+        }
+
+        tx.commit().unwrap();
+        println!("commit");
+        io::stdout().flush().unwrap();
+        pause(rng);
+    }
+}
+
+fn reader(w: &Wabl, rng: &mut XorShiftRng, count: u32) {
+    let mut last = 0;
+    for _ in 0..count {
+        let mut tx = w.begin_read().unwrap();
+        let n = counter(&tx.read_page(1).unwrap());
+        assert!(n >= last, "counter went backwards from {} to {}", last, n);
+        last = n;
+
+        for p in 2..PAGES + 1 {
+            pause(rng);
+            assert_eq!(counter(&tx.read_page(p).unwrap()), n, "inconsistent snapshot");
+        }
+    }
+}
+
+fn checkpointer(mut w: Wabl, rng: &mut XorShiftRng, count: u32) {
+    w.set_busy_handler(BusyHandler::Timeout(Duration::from_millis(100)));
+    let modes = [CheckpointMode::Passive, CheckpointMode::Full,
+                 CheckpointMode::Restart, CheckpointMode::Truncate];
+    for _ in 0..count {
+        match w.checkpoint(*rng.choose(&modes).unwrap()) {
+            Ok(_) | Err(Error(ErrorKind::Busy, _)) => { }
+            Err(e) => panic!("{}", e),
+        }
+        thread::sleep(Duration::from_micros(rng.gen_range(0, 5000)));
+    }
+}
+
+#[test]
+fn readers_writers_and_checkpointers() {
+    for_each_seed("readers_writers_and_checkpointers", readers_writers_and_checkpointers_run);
+}
+
+fn readers_writers_and_checkpointers_run(seed: u32) {
+    let dir = create("rwc");
+    let mut children = Vec::new();
+    for i in 0..3 {
+        children.push(spawn("writer", &dir, seed.wrapping_add(i), 150));
+    }
+    for i in 3..6 {
+        children.push(spawn("reader", &dir, seed.wrapping_add(i), 500));
+    }
+    children.push(spawn("checkpointer", &dir, seed.wrapping_add(6), 100));
+
+    let mut commits = 0;
+    for child in children {
+        let (ok, n) = wait(child);
+        assert!(ok, "a child failed");
+        commits += n;
+    }
+
+    assert_eq!(commits, 450);
+    assert_eq!(final_counter(&dir), commits);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn killed_writer() {
+    for_each_seed("killed_writer", killed_writer_run);
+}
+
+fn killed_writer_run(seed: u32) {
+    let dir = create("killed");
+    let mut victim = spawn("writer", &dir, seed, 100000);
+    let mut children = Vec::new();
+    for i in 1..3 {
+        children.push(spawn("writer", &dir, seed.wrapping_add(i), 200));
+    }
+    children.push(spawn("reader", &dir, seed.wrapping_add(3), 1000));
+    children.push(spawn("checkpointer", &dir, seed.wrapping_add(4), 100));
+
+    // Kill it while it is likely to hold the write lock or be in the
+    // middle of writing frames
+    thread::sleep(Duration::from_millis(300));
+    victim.kill().unwrap();
+    let (_, victim_commits) = wait(victim);
+
+    let mut commits = victim_commits;
+    for child in children {
+        let (ok, n) = wait(child);
+        assert!(ok, "a child failed");
+        commits += n;
+    }
+
+    // The victim may have committed once more without reporting it
+    let n = final_counter(&dir);
+    assert!(n == commits || n == commits + 1,
+            "counter is {} after {} reported commits", n, commits);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,98 @@
+//! Rebuilding the shared index while other connections use it
+
+extern crate btrs;
+
+mod common;
+
+use btrs::errors::*;
+use btrs::lock::BusyHandler;
+use btrs::mem_vfs::MemVfs;
+use btrs::vfs::Vfs;
+use btrs::wal_index::REGION_SIZE;
+use common::*;
+use std::ptr;
+use std::sync::Arc;
+
+/// Damages the index header so the next connection to look at it
+/// has to rebuild the index from the log
+fn clobber_index_header(vfs: &MemVfs) {
+    let shm = vfs.open_shm(&path("shm")).unwrap();
+    let region = shm.region(0, REGION_SIZE).unwrap();
+    unsafe {
+        ptr::write_bytes(region, 0, 8);
+    }
+}
+
+#[test]
+fn reader_spans_recovery() {
+    let vfs = MemVfs::new();
+    let w1 = open(&vfs);
+    write(&w1, 1, 1);
+    write(&w1, 2, 2);
+
+    let mut w2 = open(&vfs);
+    let mut reader = w1.begin_read().unwrap();
+    clobber_index_header(&vfs);
+
+    // Recovery waits for the reader rather than rebuilding the index
+    // out from under it
+    w2.set_busy_handler(BusyHandler::Callback(Arc::new(|_| false)));
+    match w2.begin_read() {
+        Err(Error(ErrorKind::Busy, _)) => { }
+        r => panic!("expected Busy, got {:?}", r.err()),
+    }
+    match w2.begin_read().and_then(|tx| tx.begin_write()) {
+        Err(Error(ErrorKind::Busy, _)) => { }
+        r => panic!("expected Busy, got {:?}", r.err()),
+    }
+
+    assert_eq!(reader.read_page(1).unwrap().buf()[0], 1);
+    assert_eq!(reader.read_page(2).unwrap().buf()[0], 2);
+    drop(reader);
+
+    // With the reader gone the index is rebuilt with every commit
+    let mut tx = w2.begin_read().unwrap();
+    assert_eq!(tx.read_page(1).unwrap().buf()[0], 1);
+    assert_eq!(tx.read_page(2).unwrap().buf()[0], 2);
+    drop(tx);
+    write(&w2, 3, 3);
+    let mut tx = w1.begin_read().unwrap();
+    assert_eq!(tx.read_page(3).unwrap().buf()[0], 3);
+    assert!(w1.check_integrity().unwrap().is_empty());
+}
+
+#[test]
+fn stale_frames_of_a_recreated_log() {
+    let vfs = MemVfs::new();
+    let w = open(&vfs);
+    write(&w, 1, 1);
+    write(&w, 2, 2);
+    drop(w);
+    let file = vfs.open(&path("wal")).unwrap();
+    let mut old = vec![0; file.len().unwrap() as usize];
+    file.read_at(&mut old, 0).unwrap();
+
+    // The log is deleted and recreated, starting over at epoch 0 with
+    // a new salt, but the old frames are still there past the header
+    file.set_len(0).unwrap();
+    drop(open(&vfs));
+    let header_len = file.len().unwrap();
+    let mut header = vec![0; header_len as usize];
+    file.read_at(&mut header, 0).unwrap();
+    assert!(header[..] != old[..header_len as usize]);
+    file.write_at(&old[header_len as usize..], header_len).unwrap();
+
+    // Recovery doesn't take them for frames of the new log
+    clobber_index_header(&vfs);
+    let w = open(&vfs);
+    let mut tx = w.begin_read().unwrap();
+    assert_eq!(tx.db_size().unwrap(), 1);
+    match tx.read_page(1) {
+        Err(Error(ErrorKind::PageOutOfRange(1, 1), _)) => { }
+        r => panic!("expected PageOutOfRange, got {:?}", r.err()),
+    }
+    drop(tx);
+    write(&w, 1, 3);
+    assert_eq!(read(&w, 1), 3);
+    assert!(w.check_integrity().unwrap().is_empty());
+}
@@ -0,0 +1,88 @@
+//! Transactions that don't commit, whether rolled back, dropped or
+//! failed part way, leave no trace
+
+extern crate btrs;
+extern crate libc;
+
+mod common;
+
+use btrs::fault_vfs::{FaultVfs, Op};
+use btrs::mem_vfs::MemVfs;
+use btrs::vfs::Vfs;
+use btrs::wabl::*;
+use btrs::wal::Synchronous;
+use common::page;
+
+fn open<V: Vfs + Clone + 'static>(vfs: &V) -> Wabl {
+    let mut w = common::open(vfs);
+    w.set_synchronous(Synchronous::Full);
+    w
+}
+
+fn commit(w: &Wabl, pages: &[(u32, u8)]) -> btrs::errors::Result<()> {
+    let mut tx = w.begin_write()?;
+    for &(n, byte) in pages {
+        tx.write_page(n, page(byte))?;
+    }
+    tx.commit()
+}
+
+/// The first byte of each page and the change counter
+fn contents(w: &Wabl) -> (Vec<u8>, u32) {
+    let mut tx = w.begin_read().unwrap();
+    let pages = (1..tx.db_size().unwrap()).map(|n| tx.read_page(n).unwrap().buf()[0]).collect();
+    (pages, tx.header().unwrap().change_counter)
+}
+
+#[test]
+fn rollback_and_drop() {
+    let w = open(&MemVfs::new());
+    commit(&w, &[(1, 1), (2, 2)]).unwrap();
+    let before = contents(&w);
+
+    let mut tx = w.begin_write().unwrap();
+    tx.write_page(1, page(9)).unwrap();
+    tx.write_page(3, page(9)).unwrap();
+    tx.set_user_version(9).unwrap();
+    assert_eq!(tx.read_page(1).unwrap().buf()[0], 9);
+    tx.rollback().unwrap();
+    assert_eq!(contents(&w), before);
+
+    // Dropping a transaction rolls it back too
+    {
+        let mut tx = w.begin_write().unwrap();
+        tx.write_page(2, page(9)).unwrap();
+        tx.set_db_size(2).unwrap();
+    }
+    assert_eq!(contents(&w), before);
+    assert_eq!(w.begin_read().unwrap().header().unwrap().user_version, 0);
+
+    // Neither holds up the next writer
+    commit(&w, &[(2, 3)]).unwrap();
+    assert_eq!(contents(&w).0, vec![1, 3]);
+}
+
+#[test]
+fn abandoned_frames_are_overwritten() {
+    let vfs = FaultVfs::new(0);
+    let w = open(&vfs);
+    commit(&w, &[(1, 1)]).unwrap();
+
+    // The frames reach the log, but the commit fails before it
+    // publishes them
+    vfs.fail_after(Some(Op::Sync), 0, libc::EIO);
+    assert!(commit(&w, &[(2, 2), (3, 2), (4, 2)]).is_err());
+    assert_eq!(contents(&w).0, vec![1]);
+
+    // The next commit reuses the abandoned frames, and only part of
+    // them, leaving the old commit frame after its own
+    commit(&w, &[(1, 3)]).unwrap();
+    assert_eq!(contents(&w).0, vec![3]);
+
+    // Recovering the log from scratch finds the same
+    drop(w);
+    vfs.crash();
+    let w = open(&vfs);
+    assert_eq!(contents(&w).0, vec![3]);
+    assert!(w.check_integrity().unwrap().is_empty());
+}
@@ -0,0 +1,78 @@
+//! One connection shared between threads
+
+extern crate btrs;
+
+mod common;
+
+use btrs::mem_vfs::MemVfs;
+use btrs::page::Page;
+use btrs::units::PageSize;
+use btrs::wabl::*;
+use common::{try_open, PAGE_SIZE};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+const PAGES: u32 = 4;
+const READERS: usize = 4;
+const COMMITS: u32 = 200;
+
+/// Every commit writes the same value to every page, so a reader
+/// that sees pages from two commits has a torn snapshot
+#[test]
+fn readers_see_whole_commits() {
+    let vfs = MemVfs::new();
+    let mut w = try_open(&vfs).unwrap();
+    // Checkpoint often, so readers race checkpoints and log restarts
+    w.set_auto_checkpoint(AutoCheckpoint::Frames(10));
+    let w = Arc::new(w);
+    let done = Arc::new(AtomicBool::new(false));
+
+    let readers = (0..READERS).map(|_| {
+        let w = w.clone();
+        let done = done.clone();
+        thread::spawn(move || {
+            let mut last = 0;
+            let mut reads = 0;
+            while !done.load(Ordering::SeqCst) || reads == 0 {
+                let mut tx = w.begin_read().unwrap();
+                if tx.db_size().unwrap() <= PAGES {
+                    continue;
+                }
+                let values = (1..PAGES + 1).map(|n| {
+                    tx.read_page(n).unwrap().buf()[0]
+                }).collect::<Vec<_>>();
+                assert!(values.iter().all(|&v| v == values[0]),
+                        "torn snapshot {:?}", values);
+                assert!(values[0] >= last, "went back from {} to {}", last, values[0]);
+                last = values[0];
+                reads += 1;
+            }
+        })
+    }).collect::<Vec<_>>();
+
+    let writer = {
+        let w = w.clone();
+        thread::spawn(move || {
+            for i in 1..COMMITS + 1 {
+                let mut tx = w.begin_read().unwrap().begin_write().unwrap();
+                for n in 1..PAGES + 1 {
+                    let mut page = Page::new(PageSize::new(PAGE_SIZE));
+                    page.buf_mut()[0] = i as u8;
+                    tx.write_page(n, page).unwrap();
+                }
+                tx.commit().unwrap();
+            }
+        })
+    };
+
+    writer.join().unwrap();
+    done.store(true, Ordering::SeqCst);
+    for r in readers {
+        r.join().unwrap();
+    }
+
+    let mut tx = w.begin_read().unwrap();
+    assert_eq!(tx.read_page(PAGES).unwrap().buf()[0], COMMITS as u8);
+    assert!(w.check_integrity().unwrap().is_empty());
+}